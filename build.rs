@@ -1,19 +1,20 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script writes a `memory.x` for the chip selected by the cargo
+//! features into a directory where the linker can always find it at build time.
+//! With the `ota` feature, the loader and the firmware each get their own.
 //!
 //! The build script also sets the linker flags to tell it which link script to use.
 
+#[path = "build/keyboard_uid.rs"]
+mod keyboard_uid;
+#[path = "build/kle.rs"]
+mod kle;
+
 use const_gen::*;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 use xz2::read::XzEncoder;
 
@@ -23,15 +24,45 @@ fn main() {
     //println!("cargo:rerun-if-changed=keyboard.toml");
 
     generate_vial_config();
+    generate_partitions();
+    generate_build_info();
 
-    // Put `memory.x` in our output directory and ensure it's
+    // Put `memory.x` for the selected chip in our output directory and ensure it's
     // on the linker search path.
-    // let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    // File::create(out.join("memory.x"))
-    //     .unwrap()
-    //     .write_all(include_bytes!("memory.x"))
-    //     .unwrap();
-    // println!("cargo:rustc-link-search={}", out.display());
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let ota = env::var_os("CARGO_FEATURE_OTA").is_some();
+    if ota {
+        // The loader sits at the start of the flash and the firmware behind it,
+        // so every binary gets its own `memory.x`
+        let layout = FlashLayout::new();
+        for (bin, origin, length) in [
+            ("loader", 0, layout.loader_size),
+            ("central", layout.active_offset, layout.central_active_size),
+            (
+                "peripheral",
+                layout.active_offset,
+                layout.peripheral_active_size,
+            ),
+        ] {
+            let dir = out.join(bin);
+            fs::create_dir_all(&dir).unwrap();
+            File::create(dir.join("memory.x"))
+                .unwrap()
+                .write_all(stm32_memory_x(origin, length).as_bytes())
+                .unwrap();
+            File::create(dir.join("size_check.x"))
+                .unwrap()
+                .write_all(size_check_x(bin, length).as_bytes())
+                .unwrap();
+            println!("cargo:rustc-link-arg-bin={bin}=-L{}", dir.display());
+        }
+    } else {
+        File::create(out.join("memory.x"))
+            .unwrap()
+            .write_all(generate_memory_x().as_bytes())
+            .unwrap();
+        println!("cargo:rustc-link-search={}", out.display());
+    }
 
     // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
     // for example the FLASH and RAM sections in your `memory.x`.
@@ -44,17 +75,191 @@ fn main() {
     // Set the extra linker script from defmt
     println!("cargo:rustc-link-arg=-Tdefmt.x");
 
-    // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // Fail the link of a binary that outgrows its partition, after `link.x` placed its sections
+    if ota {
+        for bin in ["loader", "central", "peripheral"] {
+            println!("cargo:rustc-link-arg-bin={bin}=-Tsize_check.x");
+        }
+    }
+
+    // Place the RP2040 second stage bootloader, the linker script is provided by embassy-rp
+    if env::var_os("CARGO_FEATURE_RP2040").is_some() {
+        println!("cargo:rustc-link-arg=-Tlink-rp.x");
+    }
 
     // Specify linker arguments.
 
     // println!("cargo:rustc-linker=flip-link");
 }
 
+/// Flash and RAM sizes in KiB of the chip selected by the cargo features
+fn memory_sizes() -> (u32, u32) {
+    if env::var_os("CARGO_FEATURE_STM32F411CE").is_some() {
+        (512, 128)
+    } else if env::var_os("CARGO_FEATURE_STM32F401CE").is_some() {
+        (512, 96)
+    } else {
+        (256, 64)
+    }
+}
+
+fn generate_memory_x() -> String {
+    if env::var_os("CARGO_FEATURE_RP2040").is_some() {
+        // The first 256 bytes of the QSPI flash hold the second stage bootloader
+        return "MEMORY
+{
+  BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+  FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+  RAM : ORIGIN = 0x20000000, LENGTH = 256K
+}
+"
+        .to_owned();
+    }
+
+    let (flash_kib, _) = memory_sizes();
+    stm32_memory_x(0, flash_kib * 1024)
+}
+
+/// `memory.x` for an STM32 binary placed at `origin` bytes into the flash.
+///
+/// The last word of the RAM holds the DFU request of `src/bootloader.rs`. It is left out of `RAM`
+/// in every binary, so the loader's statics and stack don't overwrite it on the way to the
+/// firmware.
+fn stm32_memory_x(origin: u32, length: u32) -> String {
+    let (_, ram_kib) = memory_sizes();
+    let origin = 0x0800_0000 + origin;
+    format!(
+        "MEMORY
+{{
+  FLASH : ORIGIN = {origin:#010x}, LENGTH = {length:#x}
+  RAM : ORIGIN = 0x20000000, LENGTH = {ram_kib}K - 4
+  DFU_REQUEST : ORIGIN = 0x20000000 + {ram_kib}K - 4, LENGTH = 4
+}}
+
+SECTIONS
+{{
+  .dfu_request (NOLOAD) : ALIGN(4)
+  {{
+    KEEP(*(.dfu_request));
+  }} > DFU_REQUEST
+}}
+"
+    )
+}
+
+/// Linker script asserting that `bin` fits into its `length` bytes of flash. The loader would
+/// otherwise run into the boot state and the firmware into the next partition.
+fn size_check_x(bin: &str, length: u32) -> String {
+    format!(
+        "ASSERT(__sidata + SIZEOF(.data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       \"The {bin} doesn't fit into its {} KiB of flash\");
+",
+        length / 1024
+    )
+}
+
+/// Partitions of the internal flash for firmware updates, as offsets from the start of the flash.
+///
+/// Sectors of the F4 are 4x16K, 64K and then 128K, partitions have to start on a sector.
+struct FlashLayout {
+    loader_size: u32,
+    state_offset: u32,
+    state_size: u32,
+    active_offset: u32,
+    central_active_size: u32,
+    /// The peripheral keeps the update in the upper half of its own flash
+    peripheral_active_size: u32,
+    peripheral_dfu_offset: u32,
+    peripheral_dfu_size: u32,
+}
+
+impl FlashLayout {
+    fn new() -> Self {
+        let flash_size = memory_sizes().0 * 1024;
+        let half = flash_size / 2;
+        Self {
+            loader_size: 16 * 1024,
+            state_offset: 16 * 1024,
+            state_size: 16 * 1024,
+            active_offset: 32 * 1024,
+            central_active_size: flash_size - 32 * 1024,
+            peripheral_active_size: half - 32 * 1024,
+            peripheral_dfu_offset: half,
+            peripheral_dfu_size: half,
+        }
+    }
+}
+
+fn generate_partitions() {
+    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("partitions_generated.rs");
+    let layout = FlashLayout::new();
+    let const_declarations = [
+        const_declaration!(pub LOADER_SIZE = layout.loader_size),
+        const_declaration!(pub STATE_OFFSET = layout.state_offset),
+        const_declaration!(pub STATE_SIZE = layout.state_size),
+        const_declaration!(pub ACTIVE_OFFSET = layout.active_offset),
+        const_declaration!(pub CENTRAL_ACTIVE_SIZE = layout.central_active_size),
+        const_declaration!(pub PERIPHERAL_ACTIVE_SIZE = layout.peripheral_active_size),
+        const_declaration!(pub PERIPHERAL_DFU_OFFSET = layout.peripheral_dfu_offset),
+        const_declaration!(pub PERIPHERAL_DFU_SIZE = layout.peripheral_dfu_size),
+    ]
+    .join("\n");
+    fs::write(out_file, const_declarations).unwrap();
+}
+
+fn generate_build_info() {
+    // The commit changes with the index, checking out another branch changes HEAD
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("build_info_generated.rs");
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    };
+    let git_hash = match git(&["rev-parse", "--short=8", "HEAD"]) {
+        Some(hash) if git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty()) => {
+            hash + "-dirty"
+        }
+        Some(hash) => hash,
+        None => "unknown".to_owned(),
+    };
+
+    // Reproducible builds set the time themselves
+    let timestamp: u64 = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().expect("SOURCE_DATE_EPOCH is not a number"),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| Some(key.strip_prefix("CARGO_FEATURE_")?.to_owned()))
+        .filter(|feature| feature != "DEFAULT" && !feature.starts_with('_'))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let build_info = format!("{version} {git_hash} {timestamp} {}", features.join(","));
+    // Has to fit `BUILD_INFO_LEN` in src/build_info.rs
+    assert!(build_info.len() <= 128, "Build info too long: {build_info}");
+
+    let const_declaration = const_declaration!(pub BUILD_INFO = build_info);
+    fs::write(
+        out_file,
+        "#[allow(clippy::redundant_static_lifetimes)]\n".to_owned() + &const_declaration,
+    )
+    .unwrap();
+}
+
 fn generate_vial_config() {
     // Generated vial config file
     let out_file = Path::new(&env::var_os("OUT_DIR").unwrap()).join("config_generated.rs");
@@ -69,13 +274,21 @@ fn generate_vial_config() {
         Err(e) => println!("Cannot find vial.json {:?}: {}", p, e),
     };
 
-    let vial_cfg = json::stringify(json::parse(&content).unwrap());
+    let mut vial = json::parse(&content).unwrap();
+    generate_vial_layouts(&mut vial);
+    let vial_cfg = json::stringify(vial);
+    // The whole definition, to load into Vial by hand
+    fs::write(
+        Path::new(&env::var_os("OUT_DIR").unwrap()).join("vial.json"),
+        &vial_cfg,
+    )
+    .unwrap();
     let mut keyboard_def_compressed: Vec<u8> = Vec::new();
     XzEncoder::new(vial_cfg.as_bytes(), 6)
         .read_to_end(&mut keyboard_def_compressed)
         .unwrap();
 
-    let keyboard_id: Vec<u8> = vec![0xB9, 0xBC, 0x09, 0xB2, 0x9D, 0x37, 0x4C, 0xEA];
+    let keyboard_id = keyboard_uid(fnv1a(vial_cfg.as_bytes()));
     let const_declarations = [
         const_declaration!(pub VIAL_KEYBOARD_DEF = keyboard_def_compressed),
         const_declaration!(pub VIAL_KEYBOARD_ID = keyboard_id),
@@ -84,3 +297,66 @@ fn generate_vial_config() {
     .join("\n");
     fs::write(out_file, const_declarations).unwrap();
 }
+
+/// Vial's matrix and layout, from the KLE files of the halves. The right half has a layout option,
+/// so Vial can show the left half alone.
+fn generate_vial_layouts(vial: &mut json::JsonValue) {
+    if env::var_os("CARGO_FEATURE_STANDALONE").is_some() {
+        generate_standalone_layout(vial);
+        return;
+    }
+    let left = kle::read("keyboard-layout-left.json");
+    let right = kle::read("keyboard-layout-right.json");
+    let combined = kle::read("keyboard-layout.json");
+    let positions = kle::validate_halves(&left, &right, &combined)
+        .unwrap_or_else(|errors| panic!("Invalid KLE layouts:\n{}", errors.join("\n")));
+
+    let (rows, cols) = positions.iter().fold((0, 0), |(rows, cols), &(row, col)| {
+        (rows.max(row + 1), cols.max(col + 1))
+    });
+    vial["matrix"] = json::object! { rows: rows, cols: cols };
+
+    let keys: Vec<_> = left
+        .into_iter()
+        .chain(right.into_iter().map(|key| key.with_layout_option(0, 0)))
+        .collect();
+    vial["layouts"] = json::object! {
+        labels: [["Halves", "Both", "Left only"]],
+        keymap: kle::serialize(&keys),
+    };
+}
+
+/// Vial's matrix and layout of the left half running on its own, from `keyboard-layout-single.json`.
+fn generate_standalone_layout(vial: &mut json::JsonValue) {
+    let single = kle::read("keyboard-layout-single.json");
+    let left = kle::read("keyboard-layout-left.json");
+
+    let positions = kle::validate_standalone(&single, &left)
+        .unwrap_or_else(|errors| panic!("Invalid KLE layouts:\n{}", errors.join("\n")));
+
+    let (rows, cols) = positions.iter().fold((0, 0), |(rows, cols), &(row, col)| {
+        (rows.max(row + 1), cols.max(col + 1))
+    });
+    vial["matrix"] = json::object! { rows: rows, cols: cols };
+    vial["layouts"] = json::object! { keymap: kle::serialize(&single) };
+}
+
+/// The keyboard UID from `keyboard_uid.json`, see `keyboard_uid::resolve`.
+fn keyboard_uid(definition_hash: u64) -> Vec<u8> {
+    println!("cargo:rerun-if-changed=keyboard_uid.json");
+    let config = fs::read_to_string("keyboard_uid.json").ok();
+    let standalone = env::var_os("CARGO_FEATURE_STANDALONE").is_some();
+    let resolved = keyboard_uid::resolve(config.as_deref(), standalone, definition_hash)
+        .unwrap_or_else(|e| panic!("{e}"));
+    if let Some(warning) = resolved.warning {
+        println!("cargo:warning={warning}");
+    }
+    resolved.uid
+}
+
+/// 64 bit FNV-1a
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
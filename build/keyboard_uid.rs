@@ -0,0 +1,148 @@
+//! The Vial keyboard UID, from `keyboard_uid.json`.
+//!
+//! Vial caches the definition by the keyboard UID. It is set in `keyboard_uid.json`, or derived
+//! from the hash of the definition if that has none. The standalone build has a UID of its own in
+//! the `standalone` object of the file.
+
+use json::JsonValue;
+
+pub struct KeyboardUid {
+    pub uid: Vec<u8>,
+    /// Set when the definition changed since the UID was set
+    pub warning: Option<String>,
+}
+
+/// UID for the definition with `definition_hash`, `config` is the content of `keyboard_uid.json`
+pub fn resolve(
+    config: Option<&str>,
+    standalone: bool,
+    definition_hash: u64,
+) -> Result<KeyboardUid, String> {
+    let derived = KeyboardUid {
+        uid: definition_hash.to_be_bytes().to_vec(),
+        warning: None,
+    };
+    let Some(config) = config else {
+        return Ok(derived);
+    };
+    let mut config: JsonValue =
+        json::parse(config).map_err(|e| format!("Cannot parse keyboard_uid.json: {e}"))?;
+    if standalone {
+        config = config["standalone"].take();
+    }
+    let Some(uid) = config["uid"].as_str() else {
+        return Ok(derived);
+    };
+    if uid.len() != 16 || !uid.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!(
+            "The uid {uid} in keyboard_uid.json has to be 16 hex digits"
+        ));
+    }
+    let uid_bytes = (0..16)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&uid[i..i + 2], 16).unwrap())
+        .collect();
+
+    // The hash of the definition the UID was last set for
+    let hash = format!("{definition_hash:016x}");
+    let warning = (config["definition_hash"].as_str() != Some(hash.as_str())).then(|| {
+        format!(
+            "vial.json changed, but the keyboard UID {uid} in keyboard_uid.json didn't. Vial \
+             shows the layout it cached for the UID. Change the UID, or set definition_hash to \
+             {hash} if Vial can keep the cached layout."
+        )
+    });
+    Ok(KeyboardUid {
+        uid: uid_bytes,
+        warning,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "uid": "74ff21e53da09ed6",
+        "definition_hash": "7e4dc43289609e9a",
+        "standalone": { "uid": "a35da60ed36f745e", "definition_hash": "ed8261a0c585eb1c" }
+    }"#;
+
+    #[test]
+    fn uid_of_an_unchanged_definition() {
+        let resolved = resolve(Some(CONFIG), false, 0x7e4d_c432_8960_9e9a).unwrap();
+        assert_eq!(
+            resolved.uid,
+            [0x74, 0xff, 0x21, 0xe5, 0x3d, 0xa0, 0x9e, 0xd6]
+        );
+        assert!(resolved.warning.is_none());
+    }
+
+    #[test]
+    fn warns_when_the_definition_changed() {
+        let resolved = resolve(Some(CONFIG), false, 0x0123_4567_89ab_cdef).unwrap();
+        assert_eq!(
+            resolved.uid,
+            [0x74, 0xff, 0x21, 0xe5, 0x3d, 0xa0, 0x9e, 0xd6]
+        );
+        let warning = resolved.warning.unwrap();
+        assert!(warning.contains("74ff21e53da09ed6"));
+        assert!(warning.contains("set definition_hash to 0123456789abcdef"));
+    }
+
+    #[test]
+    fn standalone_has_its_own_uid() {
+        let resolved = resolve(Some(CONFIG), true, 0xed82_61a0_c585_eb1c).unwrap();
+        assert_eq!(
+            resolved.uid,
+            [0xa3, 0x5d, 0xa6, 0x0e, 0xd3, 0x6f, 0x74, 0x5e]
+        );
+        assert!(resolved.warning.is_none());
+        // The UID of the split build is no match for the standalone definition
+        assert!(
+            resolve(Some(CONFIG), true, 0x7e4d_c432_8960_9e9a)
+                .unwrap()
+                .warning
+                .is_some()
+        );
+    }
+
+    const HASH: u64 = 0x0123_4567_89ab_cdef;
+
+    fn assert_derived(resolved: KeyboardUid) {
+        assert_eq!(resolved.uid, HASH.to_be_bytes());
+        assert!(resolved.warning.is_none());
+    }
+
+    #[test]
+    fn derived_without_a_config() {
+        assert_derived(resolve(None, false, HASH).unwrap());
+    }
+
+    #[test]
+    fn standalone_derived_without_a_standalone_entry() {
+        // The standalone build doesn't take the UID of the split build
+        let config = r#"{ "uid": "74ff21e53da09ed6" }"#;
+        assert_derived(resolve(Some(config), true, HASH).unwrap());
+    }
+
+    #[test]
+    fn derived_without_a_uid_entry() {
+        assert_derived(resolve(Some("{}"), false, HASH).unwrap());
+        assert_derived(resolve(Some(r#"{ "standalone": {} }"#), true, HASH).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_uids() {
+        for uid in [
+            "74ff21e53da09ed",
+            "74ff21e53da09ed6a",
+            "74ff21e53da09edg",
+            "+4ff21e53da09ed6",
+        ] {
+            let config = format!(r#"{{ "uid": "{uid}" }}"#);
+            assert!(resolve(Some(&config), false, 0).is_err(), "{uid}");
+        }
+        assert!(resolve(Some("{ uid"), false, 0).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Keyboard layout editor (KLE) files, read into keys with absolute positions and written back as
+//! Vial reads them.
+//!
+//! Follows the semantics of kle-serial, which Vial's own parser is a port of: `x`/`y` move the
+//! cursor, `r`/`rx`/`ry` rotate the keys that follow and `rx`/`ry` also move the cursor to the
+//! rotation origin. Every row starts at `rx`, one below the previous row.
+
+use json::JsonValue;
+use std::collections::BTreeSet;
+use std::fs;
+
+#[derive(Clone)]
+pub struct Key {
+    /// Legends of the key, separated by `\n`. The first is the matrix position `row,col`.
+    pub legends: Vec<String>,
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+    pub r: f64,
+    pub rx: f64,
+    pub ry: f64,
+}
+
+impl Key {
+    /// Matrix position `(row, col)` of the key
+    pub fn matrix_position(&self) -> Option<(usize, usize)> {
+        let (row, col) = self.legends.first()?.split_once(',')?;
+        Some((row.trim().parse().ok()?, col.trim().parse().ok()?))
+    }
+
+    /// Only show the key for `choice` of the layout option `option`
+    pub fn with_layout_option(mut self, option: usize, choice: usize) -> Self {
+        // Vial reads the layout option from the bottom right legend
+        if self.legends.len() < 4 {
+            self.legends.resize(4, String::new());
+        }
+        self.legends[3] = format!("{option},{choice}");
+        self
+    }
+}
+
+pub fn read(path: &str) -> Vec<Key> {
+    println!("cargo:rerun-if-changed={path}");
+    let content = fs::read_to_string(path).unwrap_or_else(|e| panic!("Cannot read {path}: {e}"));
+    let layout = json::parse(&content).unwrap_or_else(|e| panic!("Cannot parse {path}: {e}"));
+    parse(&layout).unwrap_or_else(|e| panic!("Invalid KLE layout {path}: {e}"))
+}
+
+pub fn parse(layout: &JsonValue) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    let mut current = Key {
+        legends: Vec::new(),
+        x: 0.0,
+        y: 0.0,
+        w: 1.0,
+        h: 1.0,
+        r: 0.0,
+        rx: 0.0,
+        ry: 0.0,
+    };
+    // Keyboard metadata is an object in place of the first row
+    for row in layout.members().filter(|row| row.is_array()) {
+        for (i, item) in row.members().enumerate() {
+            if let Some(legends) = item.as_str() {
+                keys.push(Key {
+                    legends: legends.split('\n').map(str::to_owned).collect(),
+                    ..current.clone()
+                });
+                current.x += current.w;
+                current.w = 1.0;
+                current.h = 1.0;
+                continue;
+            }
+            if !item.is_object() {
+                return Err(format!("unexpected {item} in a row"));
+            }
+            let number = |name: &str| item[name].as_f64();
+            if (number("r").is_some() || number("rx").is_some() || number("ry").is_some()) && i != 0
+            {
+                return Err("rotation can only be set on the first key of a row".to_owned());
+            }
+            if let Some(r) = number("r") {
+                current.r = r;
+            }
+            if let Some(rx) = number("rx") {
+                current.rx = rx;
+                current.x = rx;
+                current.y = current.ry;
+            }
+            if let Some(ry) = number("ry") {
+                current.ry = ry;
+                current.x = current.rx;
+                current.y = ry;
+            }
+            current.x += number("x").unwrap_or(0.0);
+            current.y += number("y").unwrap_or(0.0);
+            current.w = number("w").unwrap_or(current.w);
+            current.h = number("h").unwrap_or(current.h);
+        }
+        current.y += 1.0;
+        current.x = current.rx;
+    }
+    Ok(keys)
+}
+
+/// Check that every key has a matrix position of its own and that the halves make up the
+/// combined layout. Returns the matrix positions of the keyboard.
+pub fn validate_halves(
+    left: &[Key],
+    right: &[Key],
+    combined: &[Key],
+) -> Result<BTreeSet<(usize, usize)>, Vec<String>> {
+    let mut errors = Vec::new();
+    let left = matrix_positions("keyboard-layout-left.json", left, &mut errors);
+    let right = matrix_positions("keyboard-layout-right.json", right, &mut errors);
+    let combined = matrix_positions("keyboard-layout.json", combined, &mut errors);
+
+    for (row, col) in left.intersection(&right) {
+        errors.push(format!("{row},{col} is on both halves"));
+    }
+    let halves: BTreeSet<_> = left.union(&right).copied().collect();
+    for (row, col) in combined.difference(&halves) {
+        errors.push(format!(
+            "{row},{col} of keyboard-layout.json is on neither half"
+        ));
+    }
+    for (row, col) in halves.difference(&combined) {
+        errors.push(format!("{row},{col} is missing in keyboard-layout.json"));
+    }
+
+    if errors.is_empty() {
+        Ok(halves)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check that the layout of the left half running alone has the keys of the left half. Returns its
+/// matrix positions.
+pub fn validate_standalone(
+    single: &[Key],
+    left: &[Key],
+) -> Result<BTreeSet<(usize, usize)>, Vec<String>> {
+    let mut errors = Vec::new();
+    let positions = matrix_positions("keyboard-layout-single.json", single, &mut errors);
+    let left = matrix_positions("keyboard-layout-left.json", left, &mut errors);
+    for (row, col) in positions.symmetric_difference(&left) {
+        errors.push(format!(
+            "{row},{col} is on only one of keyboard-layout-single.json and keyboard-layout-left.json"
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(positions)
+    } else {
+        Err(errors)
+    }
+}
+
+fn matrix_positions(
+    file: &str,
+    keys: &[Key],
+    errors: &mut Vec<String>,
+) -> BTreeSet<(usize, usize)> {
+    let mut positions = BTreeSet::new();
+    for key in keys {
+        match key.matrix_position() {
+            Some((row, col)) if !positions.insert((row, col)) => {
+                errors.push(format!("{file}: {row},{col} is on more than one key"))
+            }
+            Some(_) => {}
+            None => errors.push(format!(
+                "{file}: key {:?} has no `row,col` legend",
+                key.legends.join("\n")
+            )),
+        }
+    }
+    positions
+}
+
+/// KLE layout with every key in a row of its own, placed relative to its rotation origin.
+pub fn serialize(keys: &[Key]) -> JsonValue {
+    // Positions are sums of KLE's quarter and eighth units, round away float noise
+    let round = |value: f64| (value * 1e6).round() / 1e6 + 0.0;
+    let rows = keys.iter().map(|key| {
+        let mut properties = json::object! {
+            r: round(key.r),
+            rx: round(key.rx),
+            ry: round(key.ry),
+            x: round(key.x - key.rx),
+            y: round(key.y - key.ry),
+        };
+        if key.w != 1.0 {
+            properties["w"] = round(key.w).into();
+        }
+        if key.h != 1.0 {
+            properties["h"] = round(key.h).into();
+        }
+        JsonValue::Array(vec![properties, key.legends.join("\n").into()])
+    });
+    JsonValue::Array(rows.collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(layout: &str) -> Vec<Key> {
+        parse(&json::parse(layout).unwrap()).unwrap()
+    }
+
+    fn errors(result: Result<BTreeSet<(usize, usize)>, Vec<String>>) -> Vec<String> {
+        result.unwrap_err()
+    }
+
+    #[test]
+    fn positions_and_rotation() {
+        let keys = keys(
+            r#"[
+                {"name": "metadata"},
+                ["0,0", {"w": 1.5}, "0,1", "0,2"],
+                [{"x": 0.25}, "1,0"],
+                [{"r": 15, "rx": 4, "ry": 2, "y": -1}, "2,0"],
+                ["3,0"]
+            ]"#,
+        );
+        let at = |key: &Key| (key.x, key.y, key.w, key.r);
+        assert_eq!(at(&keys[0]), (0.0, 0.0, 1.0, 0.0));
+        assert_eq!(at(&keys[1]), (1.0, 0.0, 1.5, 0.0));
+        assert_eq!(at(&keys[2]), (2.5, 0.0, 1.0, 0.0));
+        assert_eq!(at(&keys[3]), (0.25, 1.0, 1.0, 0.0));
+        // Rotated rows start at the rotation origin
+        assert_eq!(at(&keys[4]), (4.0, 1.0, 1.0, 15.0));
+        assert_eq!(at(&keys[5]), (4.0, 2.0, 1.0, 15.0));
+        assert_eq!(keys[5].matrix_position(), Some((3, 0)));
+    }
+
+    #[test]
+    fn rotation_only_on_the_first_key_of_a_row() {
+        let layout = json::parse(r#"[["0,0", {"r": 10}, "0,1"]]"#).unwrap();
+        assert!(parse(&layout).is_err());
+    }
+
+    #[test]
+    fn serialized_keys_read_back_in_place() {
+        let keys = keys(r#"[["0,0", {"w": 2}, "0,1"], [{"r": -20, "rx": 3, "ry": 1}, "1,0"]]"#);
+        let read_back = parse(&serialize(&keys)).unwrap();
+        for (key, read) in keys.iter().zip(&read_back) {
+            assert_eq!(
+                (key.x, key.y, key.w, key.r, key.rx, key.ry),
+                (read.x, read.y, read.w, read.r, read.rx, read.ry)
+            );
+            assert_eq!(key.legends, read.legends);
+        }
+    }
+
+    #[test]
+    fn layout_option_in_the_bottom_right_legend() {
+        let key = keys(r#"[["0,0"]]"#).remove(0).with_layout_option(0, 1);
+        assert_eq!(key.legends, ["0,0", "", "", "0,1"]);
+        assert_eq!(key.matrix_position(), Some((0, 0)));
+    }
+
+    #[test]
+    fn halves_make_up_the_keyboard() {
+        let left = keys(r#"[["0,0", "0,1"]]"#);
+        let right = keys(r#"[["0,2"]]"#);
+        let combined = keys(r#"[["0,0", "0,1", "0,2"]]"#);
+        let positions = validate_halves(&left, &right, &combined).unwrap();
+        assert_eq!(positions, BTreeSet::from([(0, 0), (0, 1), (0, 2)]));
+    }
+
+    #[test]
+    fn duplicate_and_missing_positions() {
+        let left = keys(r#"[["0,0", "0,0", "Esc"]]"#);
+        let right = keys(r#"[["0,1"]]"#);
+        let combined = keys(r#"[["0,0", "0,1"]]"#);
+        assert_eq!(
+            errors(validate_halves(&left, &right, &combined)),
+            [
+                "keyboard-layout-left.json: 0,0 is on more than one key",
+                "keyboard-layout-left.json: key \"Esc\" has no `row,col` legend",
+            ]
+        );
+    }
+
+    #[test]
+    fn halves_that_dont_match_the_keyboard() {
+        let left = keys(r#"[["0,0", "0,1"]]"#);
+        let right = keys(r#"[["0,1", "0,2"]]"#);
+        let combined = keys(r#"[["0,0", "0,1", "1,0"]]"#);
+        assert_eq!(
+            errors(validate_halves(&left, &right, &combined)),
+            [
+                "0,1 is on both halves",
+                "1,0 of keyboard-layout.json is on neither half",
+                "0,2 is missing in keyboard-layout.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn standalone_has_the_keys_of_the_left_half() {
+        let left = keys(r#"[["0,0", "0,1"]]"#);
+        assert!(validate_standalone(&keys(r#"[["0,1", "0,0"]]"#), &left).is_ok());
+        assert_eq!(
+            errors(validate_standalone(&keys(r#"[["0,0", "0,2"]]"#), &left)),
+            [
+                "0,1 is on only one of keyboard-layout-single.json and keyboard-layout-left.json",
+                "0,2 is on only one of keyboard-layout-single.json and keyboard-layout-left.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn layouts_of_the_firmware_are_valid() {
+        let read = |file: &str| read(&format!("{}/../{file}", env!("CARGO_MANIFEST_DIR")));
+        let left = read("keyboard-layout-left.json");
+        let right = read("keyboard-layout-right.json");
+        let combined = read("keyboard-layout.json");
+        let single = read("keyboard-layout-single.json");
+        assert!(validate_halves(&left, &right, &combined).is_ok());
+        assert!(validate_standalone(&single, &left).is_ok());
+    }
+}
@@ -0,0 +1,191 @@
+//! Board configuration shared by both halves.
+
+use defmt::warn;
+#[cfg(feature = "vbus-detection")]
+use embassy_stm32::exti::ExtiInput;
+#[cfg(feature = "vbus-detection")]
+use embassy_stm32::gpio::Pull;
+use embassy_stm32::pac::RCC;
+#[cfg(feature = "vbus-detection")]
+use embassy_stm32::peripherals::{EXTI9, PA9};
+use embassy_stm32::rcc::*;
+use embassy_stm32::time::Hertz;
+
+use crate::build_info::ClockSource;
+
+#[cfg(not(any(
+    feature = "stm32f401cc",
+    feature = "stm32f401ce",
+    feature = "stm32f411ce"
+)))]
+compile_error!("Select a chip: one of `stm32f401cc`, `stm32f401ce` or `stm32f411ce`");
+
+#[cfg(any(
+    all(feature = "stm32f401cc", feature = "stm32f401ce"),
+    all(feature = "stm32f401cc", feature = "stm32f411ce"),
+    all(feature = "stm32f401ce", feature = "stm32f411ce"),
+))]
+compile_error!("Only one chip feature may be enabled");
+
+#[cfg(not(any(
+    feature = "hse-8mhz",
+    feature = "hse-16mhz",
+    feature = "hse-25mhz",
+    feature = "hsi"
+)))]
+compile_error!("Select a clock source: one of `hse-8mhz`, `hse-16mhz`, `hse-25mhz` or `hsi`");
+
+#[cfg(any(
+    all(feature = "hse-8mhz", feature = "hse-16mhz"),
+    all(feature = "hse-8mhz", feature = "hse-25mhz"),
+    all(feature = "hse-8mhz", feature = "hsi"),
+    all(feature = "hse-16mhz", feature = "hse-25mhz"),
+    all(feature = "hse-16mhz", feature = "hsi"),
+    all(feature = "hse-25mhz", feature = "hsi"),
+))]
+compile_error!("Only one clock source feature may be enabled");
+
+/// Frequency of the crystal fitted to the board, `None` if it runs from the internal HSI only.
+#[cfg(feature = "hse-8mhz")]
+const HSE_FREQ: Option<u32> = Some(8_000_000);
+#[cfg(feature = "hse-16mhz")]
+const HSE_FREQ: Option<u32> = Some(16_000_000);
+#[cfg(feature = "hse-25mhz")]
+const HSE_FREQ: Option<u32> = Some(25_000_000);
+#[cfg(feature = "hsi")]
+const HSE_FREQ: Option<u32> = None;
+
+const HSI_FREQ: u32 = 16_000_000;
+
+/// How long to wait for HSERDY before giving up on the crystal, in ms
+const HSE_STARTUP_TIMEOUT_MS: u32 = 100;
+
+/// Max SYSCLK frequency for the f401
+#[cfg(any(feature = "stm32f401cc", feature = "stm32f401ce"))]
+const SYSCLK_FREQ: u32 = 84_000_000;
+/// The f411 can do 100 MHz, but 96 MHz is the fastest that still allows an exact clk48
+#[cfg(feature = "stm32f411ce")]
+const SYSCLK_FREQ: u32 = 96_000_000;
+/// USB needs exactly 48 MHz on clk48
+const CLK48_FREQ: u32 = 48_000_000;
+
+struct PllDividers {
+    m: u32,
+    n: u32,
+    p: u32,
+    q: u32,
+}
+
+/// Find PLL dividers for `input` yielding exactly `SYSCLK_FREQ` on P and `CLK48_FREQ` on Q.
+///
+/// Evaluated at compile time, so an impossible crystal fails the build.
+const fn pll_dividers(input: u32) -> PllDividers {
+    let mut p = 2;
+    while p <= 8 {
+        // VCO output has to be within 192..=432 MHz
+        let vco = SYSCLK_FREQ * p;
+        if vco >= 192_000_000 && vco <= 432_000_000 && vco % CLK48_FREQ == 0 {
+            let q = vco / CLK48_FREQ;
+            // Smallest M first: a VCO input close to 2 MHz has the least jitter, 1 MHz is the minimum
+            let mut m = 2;
+            while m <= 63 {
+                let vco_in = input / m;
+                if input % m == 0
+                    && vco_in >= 1_000_000
+                    && vco_in <= 2_000_000
+                    && vco % vco_in == 0
+                    && q >= 2
+                    && q <= 15
+                {
+                    return PllDividers {
+                        m,
+                        n: vco / vco_in,
+                        p,
+                        q,
+                    };
+                }
+                m += 1;
+            }
+        }
+        p += 2;
+    }
+    panic!("No PLL configuration gives the SYSCLK and an exact 48 MHz clk48 for this clock source");
+}
+
+/// Dividers for the crystal, the same as `HSI_PLL` on HSI-only boards.
+const HSE_PLL: PllDividers = pll_dividers(match HSE_FREQ {
+    Some(freq) => freq,
+    None => HSI_FREQ,
+});
+const HSI_PLL: PllDividers = pll_dividers(HSI_FREQ);
+
+/// Build the embassy config: PLL from the selected clock source, max SYSCLK, 48 MHz clk48.
+///
+/// `embassy_stm32::init` waits for the HSE forever, so the crystal is probed first and the HSI is
+/// used instead if it doesn't start.
+pub(crate) fn clock_config() -> (embassy_stm32::Config, ClockSource) {
+    let mut config = embassy_stm32::Config::default();
+    let source = match HSE_FREQ {
+        Some(freq) if hse_starts() => {
+            config.rcc.hse = Some(Hse {
+                freq: Hertz(freq),
+                mode: HseMode::Oscillator,
+            });
+            config.rcc.pll_src = PllSource::HSE;
+            config.rcc.pll = Some(pll(&HSE_PLL));
+            ClockSource::Hse
+        }
+        Some(_) => {
+            warn!("HSE didn't start, falling back to HSI. USB clock accuracy is reduced!");
+            config.rcc.hsi = true;
+            config.rcc.pll_src = PllSource::HSI;
+            config.rcc.pll = Some(pll(&HSI_PLL));
+            ClockSource::HsiFallback
+        }
+        None => {
+            config.rcc.hsi = true;
+            config.rcc.pll_src = PllSource::HSI;
+            config.rcc.pll = Some(pll(&HSI_PLL));
+            ClockSource::Hsi
+        }
+    };
+    config.rcc.ahb_pre = AHBPrescaler::DIV1;
+    config.rcc.apb1_pre = APBPrescaler::DIV4;
+    config.rcc.apb2_pre = APBPrescaler::DIV2;
+    config.rcc.sys = Sysclk::PLL1_P;
+    config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+    (config, source)
+}
+
+fn pll(dividers: &PllDividers) -> Pll {
+    Pll {
+        prediv: PllPreDiv::from_bits(dividers.m as u8),
+        mul: PllMul::from_bits(dividers.n as u16),
+        // P is encoded as (div / 2) - 1
+        divp: Some(PllPDiv::from_bits((dividers.p / 2 - 1) as u8)),
+        divq: Some(PllQDiv::from_bits(dividers.q as u8)),
+        divr: None,
+    }
+}
+
+/// Turn the HSE on and wait for it to become ready. Runs before embassy init, i.e. from the HSI.
+fn hse_starts() -> bool {
+    RCC.cr().modify(|w| w.set_hseon(true));
+    for _ in 0..HSE_STARTUP_TIMEOUT_MS {
+        if RCC.cr().read().hserdy() {
+            return true;
+        }
+        cortex_m::asm::delay(HSI_FREQ / 1000);
+    }
+    RCC.cr().modify(|w| w.set_hseon(false));
+    false
+}
+
+/// The VBUS sense divider of self-powered boards, on PA9 with its EXTI channel. The OTG_FS core's
+/// own `vbus_detection` only samples PA9.
+#[cfg(feature = "vbus-detection")]
+// The peripheral has no USB
+#[allow(dead_code)]
+pub(crate) fn vbus_sense(pin: PA9, exti: EXTI9) -> ExtiInput<'static> {
+    ExtiInput::new(pin, exti, Pull::None)
+}
@@ -0,0 +1,83 @@
+//! Update waiting for the loader, recorded in the state partition of the internal flash.
+
+// The loader reads the state, the firmware writes it
+#![allow(dead_code)]
+
+use defmt::Format;
+use embassy_stm32::flash::{Blocking, Error, Flash};
+
+use crate::crc::Crc32;
+use crate::partitions::{STATE_OFFSET, STATE_SIZE};
+
+const MAGIC: u32 = 0x5550_4454;
+
+/// A central that was updated has this long to confirm it is healthy, before the watchdog resets
+/// it and the loader rolls back. The longest the watchdog can do on the LSI is about 32 s.
+pub(crate) const WATCHDOG_TIMEOUT_US: u32 = 16_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum BootState {
+    /// A verified image of the peripheral waits in its DFU partition
+    InstallInternal { size: u32, crc: u32 },
+    /// A verified image of the central waits in the W25Q, the firmware is backed up first
+    InstallExternal { size: u32, crc: u32 },
+    /// The firmware is backed up and the image of the central is being copied
+    Swapping { size: u32, crc: u32 },
+    /// The updated central runs for the first time and hasn't confirmed yet
+    Testing,
+}
+
+impl BootState {
+    pub(crate) fn read(flash: &mut Flash<'_, Blocking>) -> Option<Self> {
+        let mut buf = [0; 16];
+        flash.blocking_read(STATE_OFFSET, &mut buf).ok()?;
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        if word(0) != MAGIC {
+            return None;
+        }
+        let (size, crc) = (word(8), word(12));
+        match word(4) {
+            0 => Some(Self::InstallInternal { size, crc }),
+            1 => Some(Self::InstallExternal { size, crc }),
+            2 => Some(Self::Swapping { size, crc }),
+            3 => Some(Self::Testing),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn write(&self, flash: &mut Flash<'_, Blocking>) -> Result<(), Error> {
+        let (kind, size, crc) = match *self {
+            Self::InstallInternal { size, crc } => (0u32, size, crc),
+            Self::InstallExternal { size, crc } => (1, size, crc),
+            Self::Swapping { size, crc } => (2, size, crc),
+            Self::Testing => (3, 0, 0),
+        };
+        clear(flash)?;
+        let mut buf = [0; 16];
+        for (i, word) in [MAGIC, kind, size, crc].into_iter().enumerate() {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        flash.blocking_write(STATE_OFFSET, &buf)
+    }
+}
+
+/// Forget a pending update.
+pub(crate) fn clear(flash: &mut Flash<'_, Blocking>) -> Result<(), Error> {
+    flash.blocking_erase(STATE_OFFSET, STATE_OFFSET + STATE_SIZE)
+}
+
+/// CRC of `size` bytes of the internal flash at `offset`
+pub(crate) fn flash_crc(
+    flash: &mut Flash<'_, Blocking>,
+    offset: u32,
+    size: u32,
+) -> Result<u32, Error> {
+    let mut crc = Crc32::new();
+    let mut buf = [0; 256];
+    for start in (offset..offset + size).step_by(buf.len()) {
+        let chunk = &mut buf[..(offset + size - start).min(256) as usize];
+        flash.blocking_read(start, chunk)?;
+        crc.update(chunk);
+    }
+    Ok(crc.finish())
+}
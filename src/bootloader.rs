@@ -0,0 +1,37 @@
+//! Entering the STM32 system memory DFU bootloader without pressing BOOT0.
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+/// System memory with ST's DFU bootloader on the f401 and f411
+const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
+const DFU_REQUEST_MAGIC: u32 = 0xDF00_B007;
+
+/// Tells the next boot to jump to the DFU bootloader. The last word of the RAM is kept out of every
+/// binary's RAM by the `memory.x` of `build.rs`, so this survives the reset and the loader.
+#[unsafe(link_section = ".dfu_request")]
+static mut DFU_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Reset and come up in the DFU bootloader.
+pub(crate) fn reboot_into_dfu() -> ! {
+    unsafe {
+        addr_of_mut!(DFU_REQUEST)
+            .cast::<u32>()
+            .write_volatile(DFU_REQUEST_MAGIC)
+    };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Jump to the DFU bootloader if the previous boot asked for it.
+///
+/// Has to run first thing in `main`, while clocks and peripherals are still in their reset state,
+/// which is what the bootloader expects.
+pub(crate) fn jump_if_requested() {
+    let request = unsafe { addr_of_mut!(DFU_REQUEST).cast::<u32>() };
+    if unsafe { request.read_volatile() } != DFU_REQUEST_MAGIC {
+        return;
+    }
+    // Only once, a reset from the bootloader starts the firmware again
+    unsafe { request.write_volatile(0) };
+    unsafe { cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32) }
+}
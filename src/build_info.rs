@@ -0,0 +1,41 @@
+//! Identification of the firmware build, generated by build.rs.
+//!
+//! `BUILD_INFO` reads `version git-hash timestamp features`, e.g.
+//! `0.2.0 1a2b3c4d 1760000000 hse-25mhz,stm32f401cc`. The hash has a `-dirty` suffix for builds of
+//! uncommitted changes, the timestamp is in seconds since the epoch.
+
+use core::cell::Cell;
+use defmt::Format;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use serde::{Deserialize, Serialize};
+
+include!(concat!(env!("OUT_DIR"), "/build_info_generated.rs"));
+
+/// Longest `BUILD_INFO` build.rs accepts
+pub(crate) const BUILD_INFO_LEN: usize = 128;
+
+/// Clock source the PLL ends up running from, reported next to the build info. The RP2040 runs
+/// from its crystal.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Format)]
+pub(crate) enum ClockSource {
+    Hse = 0,
+    Hsi = 1,
+    /// The crystal didn't start. USB runs from the less accurate HSI.
+    HsiFallback = 2,
+}
+
+static CLOCK_SOURCE: Mutex<CriticalSectionRawMutex, Cell<ClockSource>> =
+    Mutex::new(Cell::new(ClockSource::Hse));
+
+/// Clock source of this half, as set at boot. The RP2040 keeps the default.
+#[cfg_attr(feature = "rp2040", allow(dead_code))]
+pub(crate) fn clock_source() -> ClockSource {
+    CLOCK_SOURCE.lock(Cell::get)
+}
+
+#[cfg_attr(feature = "rp2040", allow(dead_code))]
+pub(crate) fn set_clock_source(source: ClockSource) {
+    CLOCK_SOURCE.lock(|cell| cell.set(source));
+}
@@ -0,0 +1,157 @@
+//! Caps Word: capitalizes a word with Shift, until a key ends the word.
+//!
+//! While the word is on, `key_filter` presses the Shift of `CAPS_WORD_SHIFT_POS` before letters and
+//! umlauts and releases it after them, so the host's Caps Lock is left alone. Digits, `ß`,
+//! Backspace, Shift and the German `-` continue the word without Shift. Any other key, a tapped
+//! tap-dance key or `TIMEOUT` without keys end it.
+
+use defmt::{info, warn};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use rmk::action::{Action, KeyAction};
+use rmk::event::KeyboardEvent;
+use rmk::keycode::KeyCode;
+
+use crate::key_filter::key_code;
+use crate::keymap::{CAPS_WORD, CAPS_WORD_SHIFT_POS, TAP_DANCE_TERM, german as g};
+
+/// Caps Word ends after this long without a key
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Key events for rmk: the key's own, and Shift before or after it
+pub(crate) type KeyEvents = Vec<KeyboardEvent, 2>;
+
+pub(crate) struct CapsWord {
+    on: bool,
+    /// Keys pressed with Shift, it is held while there are any
+    shifted: Vec<(u8, u8), 8>,
+    last_key: Instant,
+    tap_dance_pressed: Option<Instant>,
+}
+
+impl CapsWord {
+    pub(crate) fn new() -> Self {
+        Self {
+            on: false,
+            shifted: Vec::new(),
+            last_key: Instant::now(),
+            tap_dance_pressed: None,
+        }
+    }
+
+    /// When the word ends if no key follows
+    pub(crate) fn timeout(&self) -> Option<Instant> {
+        self.on.then(|| self.last_key + TIMEOUT)
+    }
+
+    /// The events rmk gets for the key at `pos` with `action`
+    pub(crate) fn key(&mut self, pos: (u8, u8), pressed: bool, action: KeyAction) -> KeyEvents {
+        let mut events = KeyEvents::new();
+        let event = KeyboardEvent::key(pos.0, pos.1, pressed);
+        if !self.on {
+            // Toggled on when the key is released
+            if !pressed && key_code(action) == Some(CAPS_WORD) {
+                info!("Caps Word on");
+                self.on = true;
+                self.last_key = Instant::now();
+            }
+            let _ = events.push(event);
+            return events;
+        }
+        self.last_key = Instant::now();
+
+        if !pressed {
+            let _ = events.push(event);
+            let shifted = self.shifted.len();
+            self.shifted.retain(|&key| key != pos);
+            if shifted > self.shifted.len() && self.shifted.is_empty() {
+                let _ = events.push(shift(false));
+            }
+            let ends = match action {
+                KeyAction::TapDance(_) => self
+                    .tap_dance_pressed
+                    .take()
+                    .is_some_and(|pressed| pressed.elapsed() < TAP_DANCE_TERM),
+                _ => key_code(action) == Some(CAPS_WORD),
+            };
+            let release = if ends { self.end() } else { None };
+            if let Some(release) = release {
+                let _ = events.push(release);
+            }
+            return events;
+        }
+
+        match action {
+            KeyAction::TapDance(_) => self.tap_dance_pressed = Some(Instant::now()),
+            KeyAction::Single(Action::Key(key)) | KeyAction::TapHold(Action::Key(key), _)
+                if is_shifted(key) =>
+            {
+                if self.shifted.is_empty() {
+                    let _ = events.push(shift(true));
+                }
+                if self.shifted.push(pos).is_err() {
+                    warn!("Too many keys held for Caps Word");
+                }
+            }
+            _ => match key_code(action) {
+                Some(CAPS_WORD) | None => {}
+                Some(key) => {
+                    // Shift would change the key, it is released before
+                    let continues = continues_word(key);
+                    let release = if continues {
+                        self.release_shift()
+                    } else {
+                        self.end()
+                    };
+                    if let Some(release) = release {
+                        let _ = events.push(release);
+                    }
+                }
+            },
+        }
+        let _ = events.push(event);
+        events
+    }
+
+    /// End the word, with the release of Shift if it is held
+    pub(crate) fn end(&mut self) -> Option<KeyboardEvent> {
+        if self.on {
+            info!("Caps Word off");
+        }
+        self.on = false;
+        self.tap_dance_pressed = None;
+        self.release_shift()
+    }
+
+    fn release_shift(&mut self) -> Option<KeyboardEvent> {
+        if self.shifted.is_empty() {
+            return None;
+        }
+        self.shifted.clear();
+        Some(shift(false))
+    }
+}
+
+fn shift(pressed: bool) -> KeyboardEvent {
+    let (row, col) = CAPS_WORD_SHIFT_POS;
+    KeyboardEvent::key(row, col, pressed)
+}
+
+/// Letters and umlauts, typed with Shift
+fn is_shifted(key: KeyCode) -> bool {
+    (KeyCode::A as u16..=KeyCode::Z as u16).contains(&(key as u16))
+        || [g::Adia, g::Odia, g::Udia].contains(&key)
+}
+
+fn continues_word(key: KeyCode) -> bool {
+    is_shifted(key)
+        || (KeyCode::Kc1 as u16..=KeyCode::Kc0 as u16).contains(&(key as u16))
+        || [
+            g::SharpS,
+            g::Minus,
+            KeyCode::Backspace,
+            KeyCode::LShift,
+            KeyCode::RShift,
+        ]
+        .contains(&key)
+}
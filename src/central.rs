@@ -3,38 +3,77 @@
 
 #[macro_use]
 mod macros;
+mod board;
+#[cfg(feature = "ota")]
+mod boot_state;
+mod bootloader;
+mod build_info;
+mod caps_word;
+mod controller;
+#[cfg(feature = "ota")]
+mod crc;
+mod dfu;
+mod dynamic_macros;
+mod key_filter;
 mod keymap;
+mod leader;
+mod link_messages;
+mod partitions;
+mod raw_hid;
+mod settings;
+mod split_link;
+mod text_macros;
+#[cfg(feature = "ota")]
+mod updater;
+#[cfg(feature = "vbus-detection")]
+mod usb;
+mod user_keys;
 mod vial;
 
-use crate::keymap::{
-    LEFT_COL, LEFT_COL_OFFSET, LEFT_ROW, LEFT_ROW_OFFSET, RIGHT_COL_OFFSET, RIGHT_ROW_OFFSET,
-    TOTAL_COL, TOTAL_ROW,
-};
+use crate::keymap::{LEFT_COL, LEFT_COL_OFFSET, LEFT_ROW, LEFT_ROW_OFFSET};
+#[cfg(not(feature = "standalone"))]
+use crate::keymap::{TOTAL_COL, TOTAL_ROW};
 use defmt::info;
 use dummy_pin::DummyPin;
+use embassy_embedded_hal::flash::partition::Partition;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
+#[cfg(feature = "ota")]
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Input, Level, Output, Speed};
 use embassy_stm32::mode::Async;
 use embassy_stm32::peripherals::USB_OTG_FS;
 use embassy_stm32::spi::{self, Spi};
-use embassy_stm32::time::Hertz;
 use embassy_stm32::usart::{BufferedInterruptHandler, BufferedUart};
 use embassy_stm32::usb::{Driver, InterruptHandler};
+#[cfg(feature = "ota")]
+use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_stm32::{bind_interrupts, peripherals, usart};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
+use heapless::String;
+use key_filter::KeyFilter;
+#[cfg(feature = "ota")]
+use partitions::{
+    CENTRAL_STAGING_OFFSET, CENTRAL_STAGING_SIZE, PERIPHERAL_STAGING_OFFSET,
+    PERIPHERAL_STAGING_SIZE,
+};
+use partitions::{
+    MACROS_OFFSET, MACROS_SIZE, SETTINGS_OFFSET, SETTINGS_SIZE, STORAGE_OFFSET, STORAGE_SIZE,
+};
+use raw_hid::RawHidDriver;
 use rmk::channel::EVENT_CHANNEL;
 use rmk::config::{
     BehaviorConfig, ControllerConfig, KeyboardUsbConfig, RmkConfig, StorageConfig, VialConfig,
 };
 use rmk::debounce::default_debouncer::DefaultDebouncer;
-use rmk::futures::future::join4;
+use rmk::futures::future::{join, join3, join4};
 use rmk::input_device::Runnable;
 use rmk::keyboard::Keyboard;
 use rmk::light::LightController;
-use rmk::split::central::{CentralMatrix, run_peripheral_manager};
+use rmk::split::central::CentralMatrix;
 use rmk::{initialize_keymap_and_storage, run_devices, run_rmk};
+use split_link::SplitLink;
 use static_cell::StaticCell;
 use vial::{VIAL_KEYBOARD_DEF, VIAL_KEYBOARD_ID};
 use w25::W25;
@@ -48,52 +87,36 @@ bind_interrupts!(struct Irqs {
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
+    // Before anything touches the clocks
+    bootloader::jump_if_requested();
+
     // RCC config
-    let config = {
-        use embassy_stm32::rcc::*;
-        let mut config = embassy_stm32::Config::default();
-        config.rcc.hse = Some(Hse {
-            freq: Hertz(25_000_000),
-            mode: HseMode::Oscillator,
-        });
-        config.rcc.pll_src = PllSource::HSE;
-        config.rcc.pll = Some(Pll {
-            prediv: PllPreDiv::DIV25,
-            mul: PllMul::MUL336,
-            divp: Some(PllPDiv::DIV4), // 8mhz / 4 * 168 / 4 = 84Mhz. (=MAX SYSCLK FREQENCY FOR f401)
-            divq: Some(PllQDiv::DIV7), // 8mhz / 4 * 168 / 7 = 48Mhz. (=Needed for clk48)
-            divr: None,
-        });
-        config.rcc.ahb_pre = AHBPrescaler::DIV1;
-        config.rcc.apb1_pre = APBPrescaler::DIV4;
-        config.rcc.apb2_pre = APBPrescaler::DIV2;
-        config.rcc.sys = Sysclk::PLL1_P;
-        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
-        config
-    };
+    let (config, clock_source) = board::clock_config();
 
     // Initialize peripherals
     info!("Embassy Init Pre");
     let p = embassy_stm32::init(config);
-    info!("Embassy Init");
+    info!("Embassy Init, clock source: {}", clock_source);
+    build_info::set_clock_source(clock_source);
+    info!("Firmware {}", build_info::BUILD_INFO);
 
     // Usb config
     static EP_OUT_BUFFER: StaticCell<[u8; 1024]> = StaticCell::new();
     let mut usb_config = embassy_stm32::usb::Config::default();
 
-    // Do not enable vbus_detection. This is a safe default that works in all boards.
+    // vbus_detection is off by default. This is a safe default that works in all boards.
     // However, if your USB device is self-powered (can stay powered on if USB is unplugged), you need
-    // to enable vbus_detection to comply with the USB spec. If you enable it, the board
+    // to enable the `vbus-detection` feature to comply with the USB spec. If you enable it, the board
     // has to support it or USB won't work at all. See docs on `vbus_detection` for details.
-    usb_config.vbus_detection = false;
-    let driver = Driver::new_fs(
+    usb_config.vbus_detection = cfg!(feature = "vbus-detection");
+    let driver = RawHidDriver::new(Driver::new_fs(
         p.USB_OTG_FS,
         Irqs,
         p.PA12,
         p.PA11,
         &mut EP_OUT_BUFFER.init([0; 1024])[..],
         usb_config,
-    );
+    ));
 
     // Pin config
     // COL 2 ROW
@@ -128,6 +151,8 @@ async fn main(_spawner: Spawner) {
     let wp = DummyPin::new_high();
     let flash_chip = W25::<w25::Q, _, _, _>::new(flash_spi, hold, wp, 8 * 1024 * 1024).unwrap();
     //let flash = async_flash_wrapper(flashChip);
+    let flash_chip = Mutex::<NoopRawMutex, _>::new(flash_chip);
+    let storage_flash = Partition::new(&flash_chip, STORAGE_OFFSET, STORAGE_SIZE);
 
     // Keyboard config
     let rmk_config = RmkConfig {
@@ -137,23 +162,38 @@ async fn main(_spawner: Spawner) {
             pid: 0xbef2,
             manufacturer: "Nionidh",
             product_name: "Nio Paws 2",
-            serial_number: "vial:f64c2b3c:000001",
+            serial_number: usb_serial_number(),
         },
         ..Default::default()
     };
 
     // Initialize the storage and keymap
     info!("Initializing storage and keymap");
+    #[cfg(not(feature = "standalone"))]
     let mut default_keymap = keymap::get_default_keymap();
-    let behavior_config = BehaviorConfig::default();
+    #[cfg(feature = "standalone")]
+    let mut default_keymap = keymap::get_standalone_keymap();
+    let mut settings_flash = Partition::new(&flash_chip, SETTINGS_OFFSET, SETTINGS_SIZE);
+    let tap_hold = settings::tap_hold_config(&mut settings_flash).await;
+    let prior_idle_time = tap_hold.prior_idle_time;
+    let behavior_config = BehaviorConfig {
+        tap_hold,
+        combo: keymap::get_combos_config(&default_keymap),
+        tap_dance: keymap::get_tap_dances_config(),
+        tri_layer: Some(keymap::TRI_LAYER),
+        one_shot: keymap::get_one_shot_config(),
+        fork: keymap::get_forks_config(),
+        keyboard_macros: text_macros::get_keyboard_macros_config(),
+        ..Default::default()
+    };
     let storage_config = StorageConfig {
         start_addr: 4096,
         num_sectors: 8,
-        clear_storage: true,
+        clear_storage: false,
     };
     let (keymap, mut storage) = initialize_keymap_and_storage(
         &mut default_keymap,
-        flash_chip,
+        storage_flash,
         &storage_config,
         behavior_config,
     )
@@ -162,13 +202,18 @@ async fn main(_spawner: Spawner) {
 
     // Initialize the matrix + keyboard
     let debouncer = DefaultDebouncer::<LEFT_ROW, LEFT_COL>::new();
-    let mut matrix =
+    let matrix =
         CentralMatrix::<_, _, _, LEFT_ROW_OFFSET, LEFT_COL_OFFSET, LEFT_ROW, LEFT_COL>::new(
             input_pins,
             output_pins,
             debouncer,
         );
+    // rmk reads the keys of both halves through the filter
+    let mut keys = KeyFilter::new(matrix, prior_idle_time);
+    #[cfg(not(feature = "standalone"))]
     let mut keyboard = Keyboard::<TOTAL_ROW, TOTAL_COL, _, _>::new(&keymap);
+    #[cfg(feature = "standalone")]
+    let mut keyboard = Keyboard::<LEFT_ROW, LEFT_COL, _, _>::new(&keymap);
 
     info!("Created Keyboard");
 
@@ -189,24 +234,93 @@ async fn main(_spawner: Spawner) {
         usart::Config::default(),
     )
     .unwrap();
+    let (uart_tx, uart_rx) = uart.split();
+    let link = SplitLink::new(uart_tx, uart_rx);
+
+    // Watch for cable unplug/replug
+    #[cfg(feature = "vbus-detection")]
+    let vbus_monitor = usb::run_vbus_monitor(board::vbus_sense(p.PA9, p.EXTI9));
+    #[cfg(not(feature = "vbus-detection"))]
+    let vbus_monitor = core::future::pending::<()>();
+
+    // Receive firmware updates of both halves
+    #[cfg(feature = "ota")]
+    let mut updater = updater::Updater::new(
+        Partition::new(&flash_chip, CENTRAL_STAGING_OFFSET, CENTRAL_STAGING_SIZE),
+        Partition::new(
+            &flash_chip,
+            PERIPHERAL_STAGING_OFFSET,
+            PERIPHERAL_STAGING_SIZE,
+        ),
+        Flash::new_blocking(p.FLASH),
+    );
+    #[cfg(feature = "ota")]
+    let updater = join(
+        updater.run(),
+        updater::run_watchdog(IndependentWatchdog::new(
+            p.IWDG,
+            boot_state::WATCHDOG_TIMEOUT_US,
+        )),
+    );
+    #[cfg(not(feature = "ota"))]
+    let updater = core::future::pending::<()>();
 
     info!("Starting!");
     // Start
-    join4(
-        run_devices! (
-            (matrix) => EVENT_CHANNEL,
+    join3(
+        join3(
+            run_devices! (
+                (keys) => EVENT_CHANNEL,
+            ),
+            keyboard.run(),
+            run_rmk(
+                &keymap,
+                driver,
+                &mut storage,
+                &mut light_controller,
+                rmk_config,
+            ),
         ),
-        keyboard.run(),
-        run_peripheral_manager::<LEFT_ROW, LEFT_COL, RIGHT_ROW_OFFSET, RIGHT_COL_OFFSET, _>(
-            0, uart,
+        join4(
+            link.run(),
+            join(
+                link_messages::run_link_messages(),
+                controller::run_controller_events(&[
+                    &user_keys::EVENTS,
+                    &key_filter::EVENTS,
+                    &dynamic_macros::EVENTS,
+                ]),
+            ),
+            user_keys::run_user_keys(),
+            dfu::run_dfu_requests(),
         ),
-        run_rmk(
-            &keymap,
-            driver,
-            &mut storage,
-            &mut light_controller,
-            rmk_config,
+        join4(
+            vbus_monitor,
+            updater,
+            join(
+                key_filter::run_active_layer(),
+                dynamic_macros::run_dynamic_macros(Partition::new(
+                    &flash_chip,
+                    MACROS_OFFSET,
+                    MACROS_SIZE,
+                )),
+            ),
+            settings::run_settings(
+                settings_flash,
+                Partition::new(&flash_chip, STORAGE_OFFSET, STORAGE_SIZE),
+            ),
         ),
     )
     .await;
 }
+
+/// Serial number from the MCU's unique ID, so boards can be told apart. Vial looks for the prefix.
+fn usb_serial_number() -> &'static str {
+    static SERIAL_NUMBER: StaticCell<String<38>> = StaticCell::new();
+    let serial_number = SERIAL_NUMBER.init(String::new());
+    serial_number.push_str("vial:f64c2b3c:").unwrap();
+    serial_number
+        .push_str(embassy_stm32::uid::uid_hex())
+        .unwrap();
+    serial_number
+}
@@ -0,0 +1,190 @@
+#![no_main]
+#![no_std]
+
+#[macro_use]
+mod macros;
+mod build_info;
+mod caps_word;
+mod controller;
+mod key_filter;
+mod keymap;
+mod leader;
+mod link_messages;
+mod split_link;
+mod text_macros;
+mod vial;
+
+use crate::keymap::{LEFT_COL, LEFT_COL_OFFSET, LEFT_ROW, LEFT_ROW_OFFSET, TOTAL_COL, TOTAL_ROW};
+use core::fmt::Write;
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_rp::bind_interrupts;
+use embassy_rp::flash::{Async, Flash};
+use embassy_rp::gpio::{Input, Output};
+use embassy_rp::peripherals::{FLASH, UART0, USB};
+use embassy_rp::uart::{self, BufferedInterruptHandler, BufferedUart};
+use embassy_rp::usb::{Driver, InterruptHandler};
+use heapless::String;
+use key_filter::KeyFilter;
+use rmk::channel::EVENT_CHANNEL;
+use rmk::config::{
+    BehaviorConfig, ControllerConfig, KeyboardUsbConfig, RmkConfig, StorageConfig, VialConfig,
+};
+use rmk::debounce::default_debouncer::DefaultDebouncer;
+use rmk::futures::future::{join, join3, join4};
+use rmk::input_device::Runnable;
+use rmk::keyboard::Keyboard;
+use rmk::light::LightController;
+use rmk::split::central::CentralMatrix;
+use rmk::{initialize_keymap_and_storage, run_devices, run_rmk};
+use split_link::SplitLink;
+use static_cell::StaticCell;
+use vial::{VIAL_KEYBOARD_DEF, VIAL_KEYBOARD_ID};
+
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    USBCTRL_IRQ => InterruptHandler<USB>;
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+/// QSPI flash of the Pro Micro RP2040, smallest variant
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    // Initialize peripherals
+    info!("Embassy Init Pre");
+    let p = embassy_rp::init(Default::default());
+    info!("Embassy Init");
+
+    // Usb config
+    let driver = Driver::new(p.USB, Irqs);
+
+    // Pin config
+    // COL 2 ROW
+    // Pins of the Pro Micro footprint, rows on the analog side, columns along the long edge
+    let (input_pins, output_pins) = config_matrix_pins_rp!(peripherals: p,
+        input: [PIN_29, PIN_28, PIN_27, PIN_26, PIN_22],
+        output: [PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_7, PIN_8, PIN_9]
+    );
+
+    // Storage lives in the last sectors of the QSPI flash, next to the firmware
+    let mut flash = Flash::<_, Async, FLASH_SIZE>::new(p.FLASH, p.DMA_CH0);
+
+    // Keyboard config
+    let rmk_config = RmkConfig {
+        vial_config: VialConfig::new(VIAL_KEYBOARD_ID, VIAL_KEYBOARD_DEF),
+        usb_config: KeyboardUsbConfig {
+            vid: 0xfeed,
+            pid: 0xbef2,
+            manufacturer: "Nionidh",
+            product_name: "Nio Paws 2",
+            serial_number: usb_serial_number(&mut flash),
+        },
+        ..Default::default()
+    };
+
+    // Initialize the storage and keymap
+    info!("Initializing storage and keymap");
+    // Without the keys of the features the STM32 central has on top
+    let mut default_keymap = keymap::get_rp2040_keymap();
+    // As the STM32 central's, without the settings the host saves in its W25Q
+    let tap_hold = keymap::get_tap_hold_config();
+    let prior_idle_time = tap_hold.prior_idle_time;
+    let behavior_config = BehaviorConfig {
+        tap_hold,
+        combo: keymap::get_combos_config(&default_keymap),
+        tap_dance: keymap::get_tap_dances_config(),
+        tri_layer: Some(keymap::TRI_LAYER),
+        one_shot: keymap::get_one_shot_config(),
+        fork: keymap::get_forks_config(),
+        keyboard_macros: text_macros::get_keyboard_macros_config(),
+        ..Default::default()
+    };
+    let storage_config = StorageConfig {
+        num_sectors: 8,
+        clear_storage: false,
+        ..Default::default()
+    };
+    let (keymap, mut storage) =
+        initialize_keymap_and_storage(&mut default_keymap, flash, &storage_config, behavior_config)
+            .await;
+    info!("Initialized storage and keymap");
+
+    // Initialize the matrix + keyboard
+    let debouncer = DefaultDebouncer::<LEFT_ROW, LEFT_COL>::new();
+    let matrix =
+        CentralMatrix::<_, _, _, LEFT_ROW_OFFSET, LEFT_COL_OFFSET, LEFT_ROW, LEFT_COL>::new(
+            input_pins,
+            output_pins,
+            debouncer,
+        );
+    // rmk reads the keys of both halves through the filter
+    let mut keys = KeyFilter::new(matrix, prior_idle_time);
+    let mut keyboard = Keyboard::<TOTAL_ROW, TOTAL_COL, _, _>::new(&keymap);
+
+    info!("Created Keyboard");
+
+    // Initialize the light controller
+    let mut light_controller: LightController<Output> =
+        LightController::new(ControllerConfig::default().light_config);
+
+    // Initilize UART
+    // TX on GP0, RX on GP1
+    static UART_OUT_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+    static UART_IN_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+    let uart = BufferedUart::new(
+        p.UART0,
+        Irqs,
+        p.PIN_0,
+        p.PIN_1,
+        &mut UART_OUT_BUFFER.init([0; 64])[..],
+        &mut UART_IN_BUFFER.init([0; 64])[..],
+        uart::Config::default(),
+    );
+    let (uart_tx, uart_rx) = uart.split();
+    // The same framed link as the STM32 halves, so the halves can be mixed
+    let link = SplitLink::new(uart_tx, uart_rx);
+
+    info!("Starting!");
+    // Start
+    join(
+        join3(
+            run_devices! (
+                (keys) => EVENT_CHANNEL,
+            ),
+            keyboard.run(),
+            run_rmk(
+                &keymap,
+                driver,
+                &mut storage,
+                &mut light_controller,
+                rmk_config,
+            ),
+        ),
+        join4(
+            link.run(),
+            link_messages::run_link_messages(),
+            controller::run_controller_events(&[&key_filter::EVENTS]),
+            key_filter::run_active_layer(),
+        ),
+    )
+    .await;
+}
+
+/// Serial number from the flash chip's unique ID, so boards can be told apart. Vial looks for the
+/// prefix.
+fn usb_serial_number(flash: &mut Flash<'_, FLASH, Async, FLASH_SIZE>) -> &'static str {
+    static SERIAL_NUMBER: StaticCell<String<30>> = StaticCell::new();
+    let serial_number = SERIAL_NUMBER.init(String::new());
+    serial_number.push_str("vial:f64c2b3c:").unwrap();
+    let mut uid = [0; 8];
+    if flash.blocking_unique_id(&mut uid).is_err() {
+        warn!("Failed to read the flash's unique ID");
+    }
+    for byte in uid {
+        write!(serial_number, "{:02X}", byte).unwrap();
+    }
+    serial_number
+}
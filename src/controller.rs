@@ -0,0 +1,26 @@
+//! rmk's controller events, handed to the firmware's handlers from a single subscriber.
+//!
+//! rmk's controller channel has few subscriber slots, so the handlers read channels of their own.
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use rmk::channel::CONTROLLER_CHANNEL;
+use rmk::event::ControllerEvent;
+
+/// Events of one handler. Events a busy handler has no room for are dropped.
+pub(crate) type ControllerEvents = Channel<CriticalSectionRawMutex, ControllerEvent, 16>;
+
+pub(crate) async fn run_controller_events(handlers: &[&ControllerEvents]) {
+    let mut events = CONTROLLER_CHANNEL
+        .subscriber()
+        .expect("No subscriber of rmk's controller channel left for the firmware");
+    loop {
+        let event = events.next_message_pure().await;
+        for handler in handlers {
+            if handler.try_send(event.clone()).is_err() {
+                warn!("A controller event handler is behind, dropped an event");
+            }
+        }
+    }
+}
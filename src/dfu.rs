@@ -0,0 +1,21 @@
+//! Rebooting both halves into the DFU bootloader, requested from the keymap or the host.
+
+use defmt::info;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+use crate::bootloader;
+use crate::split_link::{LINK_OUTBOX, LinkMessage};
+
+pub(crate) static DFU_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub(crate) async fn run_dfu_requests() {
+    DFU_REQUEST.wait().await;
+    info!("Rebooting both halves into DFU");
+    // Take the peripheral along, so both halves can be flashed
+    LINK_OUTBOX.send(LinkMessage::EnterDfu).await;
+    // Give the link message and the answer to the host time to go out
+    Timer::after_millis(100).await;
+    bootloader::reboot_into_dfu()
+}
@@ -0,0 +1,207 @@
+//! Dynamic macros: key events recorded with their timing, saved in the external flash and replayed
+//! on a key.
+//!
+//! A record key starts recording into its slot, the stop key or the record key again ends it.
+//! The key events `key_filter` hands rmk are recorded by matrix position, without the macro keys,
+//! and played back through the filter again. rmk acts on them like on the keys, so media keys and
+//! everything else rmk sends play back too, and home-row mods and tap dances resolve with the
+//! recorded timing. The events are streamed to and from the flash one by one.
+
+use core::pin::pin;
+use core::sync::atomic::Ordering;
+use defmt::{info, warn};
+use embassy_time::{Instant, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use rmk::event::{ControllerEvent, KeyPos, KeyboardEvent, KeyboardEventPos};
+use rmk::futures::future::{Either, select};
+use rmk::keycode::KeyCode;
+
+use crate::controller::ControllerEvents;
+use crate::key_filter::{PLAYBACK, RECORDED, RECORDING, key_code};
+use crate::keymap::{DM_PLAY, DM_RECORD, DM_STOP, is_dm_key};
+
+const MAGIC: u32 = 0x444D_4143;
+
+/// Every slot is a sector of the macro partition
+const SLOT_SIZE: u32 = 4096;
+/// `[MAGIC, events]`, written when recording stops
+const HEADER_LEN: u32 = 8;
+/// `[delay_lo, delay_hi, row, col]`, the delay since the previous event in ms
+const EVENT_LEN: u32 = 4;
+const MAX_EVENTS: u32 = (SLOT_SIZE - HEADER_LEN) / EVENT_LEN;
+
+/// Set in `col` of a press
+const PRESSED: u8 = 1 << 7;
+
+pub(crate) static EVENTS: ControllerEvents = ControllerEvents::new();
+
+/// The slot being recorded
+struct Recording {
+    slot: usize,
+    events: u32,
+    last_event: Instant,
+}
+
+enum Input {
+    MacroKey(KeyCode),
+    Recorded(Instant, KeyboardEvent),
+}
+
+pub(crate) async fn run_dynamic_macros<F: NorFlash>(mut flash: F) {
+    let mut recording: Option<Recording> = None;
+    loop {
+        let input = match select(pin!(EVENTS.receive()), pin!(RECORDED.receive())).await {
+            // The macro keys act on release
+            Either::Left((ControllerEvent::Key(event, action), _)) if !event.pressed => {
+                match key_code(action) {
+                    Some(key) if is_dm_key(key) => Input::MacroKey(key),
+                    _ => continue,
+                }
+            }
+            Either::Left(_) => continue,
+            Either::Right(((sent, event), _)) => Input::Recorded(sent, event),
+        };
+
+        match input {
+            Input::MacroKey(key) => match recording.take() {
+                Some(current) if key == DM_STOP || DM_RECORD.get(current.slot) == Some(&key) => {
+                    stop(&mut flash, current).await
+                }
+                // Playing or recording another slot waits until this one is done
+                Some(current) => recording = Some(current),
+                None => {
+                    if let Some(slot) = DM_RECORD.iter().position(|&record| record == key) {
+                        recording = start(&mut flash, slot).await;
+                    } else if let Some(slot) = DM_PLAY.iter().position(|&play| play == key) {
+                        play(&mut flash, slot).await;
+                    }
+                }
+            },
+            Input::Recorded(sent, event) => {
+                let Some(current) = recording.as_mut() else {
+                    continue;
+                };
+                match record(&mut flash, current, sent, event).await {
+                    Ok(()) if current.events < MAX_EVENTS => {}
+                    Ok(()) => {
+                        warn!("Macro {} is full", current.slot);
+                        stop(&mut flash, recording.take().unwrap()).await;
+                    }
+                    Err(_) => {
+                        warn!("Failed to record macro {}, it is left empty", current.slot);
+                        RECORDING.store(false, Ordering::Relaxed);
+                        recording = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn slot_offset(slot: usize) -> u32 {
+    slot as u32 * SLOT_SIZE
+}
+
+/// Erase the slot and start recording into it
+async fn start<F: NorFlash>(flash: &mut F, slot: usize) -> Option<Recording> {
+    let offset = slot_offset(slot);
+    if flash.erase(offset, offset + SLOT_SIZE).await.is_err() {
+        warn!("Failed to erase macro {}", slot);
+        return None;
+    }
+    info!("Recording macro {}", slot);
+    RECORDED.clear();
+    RECORDING.store(true, Ordering::Relaxed);
+    Some(Recording {
+        slot,
+        events: 0,
+        last_event: Instant::now(),
+    })
+}
+
+/// Write the key event rmk got at `sent` behind the events recorded so far
+async fn record<F: NorFlash>(
+    flash: &mut F,
+    recording: &mut Recording,
+    sent: Instant,
+    event: KeyboardEvent,
+) -> Result<(), F::Error> {
+    let KeyboardEventPos::Key(KeyPos { row, col }) = event.pos else {
+        return Ok(());
+    };
+    let delay = sent
+        .saturating_duration_since(recording.last_event)
+        .as_millis()
+        .min(u16::MAX as u64) as u16;
+    recording.last_event = sent;
+    let [delay_lo, delay_hi] = delay.to_le_bytes();
+    let col = if event.pressed { col | PRESSED } else { col };
+    let offset = slot_offset(recording.slot) + HEADER_LEN + recording.events * EVENT_LEN;
+    flash.write(offset, &[delay_lo, delay_hi, row, col]).await?;
+    recording.events += 1;
+    Ok(())
+}
+
+/// Stop recording and write the header, the events are already in the flash
+async fn stop<F: NorFlash>(flash: &mut F, recording: Recording) {
+    RECORDING.store(false, Ordering::Relaxed);
+    info!(
+        "Saving macro {} of {} events",
+        recording.slot, recording.events
+    );
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..].copy_from_slice(&recording.events.to_le_bytes());
+    if flash
+        .write(slot_offset(recording.slot), &header)
+        .await
+        .is_err()
+    {
+        warn!("Failed to save macro {}", recording.slot);
+    }
+}
+
+async fn play<F: NorFlash>(flash: &mut F, slot: usize) {
+    let offset = slot_offset(slot);
+    let mut header = [0; HEADER_LEN as usize];
+    if flash.read(offset, &mut header).await.is_err() {
+        warn!("Failed to read macro {}", slot);
+        return;
+    }
+    let word =
+        |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    if word(0) != MAGIC {
+        info!("Macro {} is empty", slot);
+        return;
+    }
+
+    info!("Playing macro {}", slot);
+    // Keys the macro holds, released when it ends
+    let mut held: Vec<(u8, u8), 16> = Vec::new();
+    for index in 0..word(4).min(MAX_EVENTS) {
+        let mut event = [0; EVENT_LEN as usize];
+        if flash
+            .read(offset + HEADER_LEN + index * EVENT_LEN, &mut event)
+            .await
+            .is_err()
+        {
+            warn!("Failed to read macro {}", slot);
+            break;
+        }
+        let [delay_lo, delay_hi, row, col] = event;
+        Timer::after_millis(u16::from_le_bytes([delay_lo, delay_hi]) as u64).await;
+        let (col, pressed) = (col & !PRESSED, col & PRESSED != 0);
+        if pressed {
+            if held.push((row, col)).is_err() {
+                warn!("Too many keys held in macro {}", slot);
+            }
+        } else {
+            held.retain(|&key| key != (row, col));
+        }
+        PLAYBACK.send(KeyboardEvent::key(row, col, pressed)).await;
+    }
+    for (row, col) in held {
+        PLAYBACK.send(KeyboardEvent::key(row, col, false)).await;
+    }
+}
@@ -0,0 +1,285 @@
+//! Key events of both halves on their way to rmk.
+//!
+//! The central's matrix and the key events of the peripheral are read through `KeyFilter`, which
+//! is rmk's input device. Firmware features that change what rmk gets of a key work on the events
+//! here, before rmk acts on them: a leader sequence, then Caps Word. They tell the keys apart by
+//! their action in the compiled keymap, on the layer rmk reported last.
+//!
+//! Dynamic macros record the events the filter hands rmk and play them back through it.
+//!
+//! rmk's flow tap makes a tap-hold key pressed within the prior idle time of the last key a tap.
+//! The presses of `FLOW_TAP_EXEMPT` keys are held back until the idle time has passed, with the
+//! events that follow them, so rmk leaves those keys to the tapping term.
+
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, Vec};
+use rmk::action::{Action, KeyAction};
+use rmk::event::{ControllerEvent, Event, KeyPos, KeyboardEvent, KeyboardEventPos};
+use rmk::futures::future::{Either, select};
+use rmk::input_device::InputDevice;
+use rmk::keycode::KeyCode;
+
+use crate::caps_word::CapsWord;
+use crate::controller::ControllerEvents;
+use crate::keymap::{self, FLOW_TAP_EXEMPT, RIGHT_COL_OFFSET, RIGHT_ROW_OFFSET};
+use crate::leader::{Leader, Outcome};
+use crate::split_link::KEYS_INBOX;
+
+/// A held back press goes to rmk this long after the idle time, so rmk sees it passed
+const IDLE_MARGIN: Duration = Duration::from_millis(5);
+
+pub(crate) static EVENTS: ControllerEvents = ControllerEvents::new();
+/// Hands rmk the releases of the keys it holds, and ends Caps Word and a leader sequence
+pub(crate) static RELEASE_ALL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Whether the key events for rmk go to `RECORDED`
+pub(crate) static RECORDING: AtomicBool = AtomicBool::new(false);
+/// Key events for rmk while a dynamic macro is recorded, with the time they were handed on
+pub(crate) static RECORDED: Channel<CriticalSectionRawMutex, (Instant, KeyboardEvent), 16> =
+    Channel::new();
+/// Key events of a dynamic macro being played, rmk gets them as they were recorded
+pub(crate) static PLAYBACK: Channel<CriticalSectionRawMutex, KeyboardEvent, 4> = Channel::new();
+/// The highest active layer, as rmk reported it
+static ACTIVE_LAYER: AtomicU8 = AtomicU8::new(0);
+
+/// Follow the active layer of rmk
+pub(crate) async fn run_active_layer() {
+    loop {
+        if let ControllerEvent::Layer(layer) = EVENTS.receive().await {
+            ACTIVE_LAYER.store(layer, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The key typed by `action`
+pub(crate) fn key_code(action: KeyAction) -> Option<KeyCode> {
+    match action {
+        KeyAction::Single(Action::Key(key) | Action::KeyWithModifier(key, _))
+        | KeyAction::TapHold(Action::Key(key), _) => Some(key),
+        _ => None,
+    }
+}
+
+/// The matrix position of a key event
+fn key_pos(event: &KeyboardEvent) -> Option<(u8, u8)> {
+    match event.pos {
+        KeyboardEventPos::Key(KeyPos { row, col }) => Some((row, col)),
+        _ => None,
+    }
+}
+
+enum Input {
+    Matrix(Event),
+    Peripheral(KeyboardEvent),
+    Playback(KeyboardEvent),
+    Timeout,
+    ReleaseAll,
+}
+
+pub(crate) struct KeyFilter<M> {
+    matrix: M,
+    prior_idle_time: Duration,
+    /// When rmk got the last key event
+    last_event: Instant,
+    /// Key events for rmk, in order
+    queue: Deque<KeyboardEvent, 32>,
+    /// The held back key and until when the queue waits for it
+    held_back: Option<((u8, u8), Instant)>,
+    /// Pressed keys and their action when pressed, the layer may change before the release
+    pressed: Vec<((u8, u8), KeyAction), 16>,
+    /// Keys rmk got the release of from `RELEASE_ALL` while they were pressed
+    released: Vec<(u8, u8), 16>,
+    leader: Leader,
+    caps_word: CapsWord,
+}
+
+impl<M: InputDevice> KeyFilter<M> {
+    /// `prior_idle_time` is the one rmk's flow tap runs with
+    pub(crate) fn new(matrix: M, prior_idle_time: Duration) -> Self {
+        Self {
+            matrix,
+            prior_idle_time,
+            last_event: Instant::now(),
+            queue: Deque::new(),
+            held_back: None,
+            pressed: Vec::new(),
+            released: Vec::new(),
+            leader: Leader::new(),
+            caps_word: CapsWord::new(),
+        }
+    }
+
+    /// A key event of a matrix
+    fn key(&mut self, row: u8, col: u8, pressed: bool) {
+        let pos = (row, col);
+        if let Some(index) = self.released.iter().position(|&key| key == pos) {
+            self.released.swap_remove(index);
+            return;
+        }
+        let action = if pressed {
+            let action = keymap::action_at(ACTIVE_LAYER.load(Ordering::Relaxed), row, col);
+            if self.pressed.push((pos, action)).is_err() {
+                warn!("Too many keys pressed to follow them all");
+            }
+            action
+        } else {
+            match self.pressed.iter().position(|&(key, _)| key == pos) {
+                Some(index) => self.pressed.swap_remove(index).1,
+                None => KeyAction::No,
+            }
+        };
+        match self.leader.key(pos, pressed, action) {
+            Outcome::Pass => {
+                // The macro keys aren't part of a macro
+                let macro_key = key_code(action).is_some_and(keymap::is_dm_key);
+                for event in self.caps_word.key(pos, pressed, action) {
+                    let record = !macro_key || key_pos(&event) != Some(pos);
+                    self.push(event, record);
+                }
+            }
+            Outcome::Swallowed => {}
+            Outcome::Matched(output) => self.tap(output),
+        }
+    }
+
+    /// Tap the key at `(row, col)`
+    fn tap(&mut self, (row, col): (u8, u8)) {
+        for pressed in [true, false] {
+            self.push(KeyboardEvent::key(row, col, pressed), true);
+        }
+    }
+
+    /// A key event for rmk, `record` if a dynamic macro is recorded
+    fn push(&mut self, event: KeyboardEvent, record: bool) {
+        let Some((row, col)) = key_pos(&event) else {
+            return;
+        };
+        match self.held_back {
+            None if event.pressed
+                && FLOW_TAP_EXEMPT.contains(&(row as usize, col as usize))
+                && self.last_event.elapsed() < self.prior_idle_time =>
+            {
+                let until = self.last_event + self.prior_idle_time + IDLE_MARGIN;
+                self.held_back = Some(((row, col), until));
+            }
+            // Released within the idle time, a tap either way
+            Some((key, _)) if !event.pressed && key == (row, col) => self.held_back = None,
+            _ => {}
+        }
+        if self.queue.push_back(event).is_err() {
+            warn!("Too many key events held back, dropped one");
+            return;
+        }
+        if record
+            && RECORDING.load(Ordering::Relaxed)
+            && RECORDED.try_send((Instant::now(), event)).is_err()
+        {
+            warn!("Dropped a key event of the macro being recorded");
+        }
+    }
+
+    fn timeout(&mut self) {
+        let now = Instant::now();
+        if self.held_back.is_some_and(|(_, until)| until <= now) {
+            self.held_back = None;
+        }
+        if self.leader.timeout().is_some_and(|until| until <= now) {
+            if let Some(output) = self.leader.time_out() {
+                self.tap(output);
+            }
+        }
+        if self.caps_word.timeout().is_some_and(|until| until <= now) {
+            self.end_caps_word();
+        }
+    }
+
+    fn end_caps_word(&mut self) {
+        if let Some(release) = self.caps_word.end() {
+            self.push(release, true);
+        }
+    }
+
+    fn release_all(&mut self) {
+        self.leader.cancel();
+        self.end_caps_word();
+        for (pos, _) in core::mem::take(&mut self.pressed) {
+            // The keys of a leader sequence never reached rmk
+            if self.leader.swallows(pos) {
+                continue;
+            }
+            self.push(KeyboardEvent::key(pos.0, pos.1, false), true);
+            // Fits, `pressed` is as long
+            let _ = self.released.push(pos);
+        }
+    }
+}
+
+impl<M: InputDevice> InputDevice for KeyFilter<M> {
+    async fn read_event(&mut self) -> Event {
+        loop {
+            let next = match self.held_back {
+                None => self.queue.pop_front(),
+                Some(_) => None,
+            };
+            if let Some(event) = next {
+                self.last_event = Instant::now();
+                return Event::Key(event);
+            }
+            let until = [
+                self.held_back.map(|(_, until)| until),
+                self.leader.timeout(),
+                self.caps_word.timeout(),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(Instant::MAX);
+            let input = match select(
+                select(
+                    select(pin!(self.matrix.read_event()), pin!(KEYS_INBOX.receive())),
+                    pin!(PLAYBACK.receive()),
+                ),
+                select(pin!(Timer::at(until)), pin!(RELEASE_ALL.wait())),
+            )
+            .await
+            {
+                Either::Left((Either::Left((Either::Left((event, _)), _)), _)) => {
+                    Input::Matrix(event)
+                }
+                Either::Left((Either::Left((Either::Right((event, _)), _)), _)) => {
+                    Input::Peripheral(event)
+                }
+                Either::Left((Either::Right((event, _)), _)) => Input::Playback(event),
+                Either::Right((Either::Left(_), _)) => Input::Timeout,
+                Either::Right((Either::Right(_), _)) => Input::ReleaseAll,
+            };
+            match input {
+                Input::Matrix(Event::Key(event)) => {
+                    if let KeyboardEventPos::Key(KeyPos { row, col }) = event.pos {
+                        self.key(row, col, event.pressed);
+                    }
+                }
+                // Not a key, nothing to do with the keys held back
+                Input::Matrix(event) => return event,
+                Input::Peripheral(event) => {
+                    if let KeyboardEventPos::Key(KeyPos { row, col }) = event.pos {
+                        self.key(
+                            row + RIGHT_ROW_OFFSET as u8,
+                            col + RIGHT_COL_OFFSET as u8,
+                            event.pressed,
+                        );
+                    }
+                }
+                // Already went through the filter while recording
+                Input::Playback(event) => self.push(event, false),
+                Input::Timeout => self.timeout(),
+                Input::ReleaseAll => self.release_all(),
+            }
+        }
+    }
+}
@@ -1,4 +1,12 @@
-use rmk::action::KeyAction;
+use embassy_time::Duration;
+use heapless::Vec;
+use rmk::action::{Action, KeyAction};
+use rmk::combo::Combo;
+use rmk::config::{CombosConfig, ForksConfig, OneShotConfig, TapDancesConfig, TapHoldConfig};
+use rmk::fork::{Fork, StateBits};
+use rmk::hid_state::HidModifiers;
+use rmk::keycode::{KeyCode, ModifierCombination};
+use rmk::tap_dance::TapDance;
 use rmk::{a, layer};
 
 pub(crate) const LEFT_COL: usize = 8;
@@ -11,11 +19,68 @@ pub(crate) const RIGHT_ROW: usize = 5;
 pub(crate) const RIGHT_COL_OFFSET: usize = LEFT_COL;
 pub(crate) const RIGHT_ROW_OFFSET: usize = 0;
 
-pub(crate) const NUM_LAYER: usize = 4;
+pub(crate) const NUM_LAYER: usize = 5;
+/// Layers above BASE
+const CONTROL: u8 = 1;
+const SPCL: u8 = 2;
+const PROG: u8 = 3;
+const ADJUST: u8 = 4;
+
+/// Conditional layer: the last layer is active while the other two are held. rmk supports a
+/// single rule, as its tri-layer.
+pub(crate) const TRI_LAYER: [u8; 3] = [CONTROL, SPCL, ADJUST];
 
 pub(crate) const TOTAL_COL: usize = LEFT_COL + RIGHT_COL;
 pub(crate) const TOTAL_ROW: usize = 5;
 
+/// Reboots both halves into the DFU bootloader, handled by the firmware in `user_keys`
+pub(crate) const DFU: KeyCode = KeyCode::User0;
+/// Forgets rmk's storage and the settings saved by the host, then reboots
+pub(crate) const RESET_STORAGE: KeyCode = KeyCode::User1;
+/// Changes the tapping term of the tap-hold keys by `TAPPING_TERM_STEP`, applied after a reboot
+pub(crate) const TAPPING_TERM_UP: KeyCode = KeyCode::User2;
+pub(crate) const TAPPING_TERM_DOWN: KeyCode = KeyCode::User3;
+pub(crate) const TAPPING_TERM_STEP: u16 = 10;
+/// Toggles Caps Word, handled by the firmware in `caps_word`
+pub(crate) const CAPS_WORD: KeyCode = KeyCode::User4;
+/// Matrix position `(row, col)` without a switch, Shift on BASE and transparent above. Caps Word
+/// presses it with the letters.
+pub(crate) const CAPS_WORD_SHIFT_POS: (u8, u8) = (4, 0);
+/// Dynamic macros, handled by the firmware in `dynamic_macros`: record into a slot, stop recording
+/// and play a slot
+pub(crate) const DM_RECORD: [KeyCode; 2] = [KeyCode::User5, KeyCode::User6];
+pub(crate) const DM_STOP: KeyCode = KeyCode::User7;
+pub(crate) const DM_PLAY: [KeyCode; 2] = [KeyCode::User8, KeyCode::User9];
+/// Starts a leader sequence, handled by the firmware in `leader`
+pub(crate) const LEADER_KEY: KeyCode = KeyCode::User10;
+
+/// Modifiers of the home-row mods
+const GUI: ModifierCombination = ModifierCombination::new_from(false, true, false, false, false);
+const ALT: ModifierCombination = ModifierCombination::new_from(false, false, true, false, false);
+const SHIFT: ModifierCombination = ModifierCombination::new_from(false, false, false, true, false);
+const CTRL: ModifierCombination = ModifierCombination::new_from(false, false, false, false, true);
+
+/// Tap-hold behaviour of the home-row mods. The host can change it over raw HID, see `settings`.
+pub(crate) fn get_tap_hold_config() -> TapHoldConfig {
+    TapHoldConfig {
+        enable_hrm: true,
+        // Typing streaks: a home-row mod pressed this soon after another key is a tap, except
+        // `FLOW_TAP_EXEMPT`, see `key_filter`
+        prior_idle_time: Duration::from_millis(120),
+        hold_timeout: Duration::from_millis(200),
+        permissive_hold: true,
+        hold_on_other_press: false,
+        // A same-hand key pressed while a home-row mod is held rolls into a tap
+        chordal_hold: true,
+        ..TapHoldConfig::default()
+    }
+}
+
+/// Tap-hold keys by matrix position `(row, col)` that flow tap leaves to the tapping term: the
+/// Shift home-row mods F and J, so capitals can be typed in a streak. `key_filter` holds their
+/// presses back until the prior idle time has passed.
+pub(crate) const FLOW_TAP_EXEMPT: [(usize, usize); 2] = [(1, 4), (1, 11)];
+
 /// Create a normal key. For example, `k!(A)` represents `KeyAction::Single(Action::Key(KeyCode::A))`
 macro_rules! k {
     ($k: expr) => {
@@ -47,33 +112,59 @@ macro_rules! algr {
     };
 }
 
-macro_rules! nokey {
-    () => {
-        rmk::action::KeyAction::Single(rmk::action::Action::Key(
-            rmk::keycode::KeyCode::ErrorUndefined,
-        ))
+/// Home-row modifier: the key when tapped, the modifier when held
+macro_rules! hrm {
+    ($k: expr, $m: expr) => {
+        rmk::action::KeyAction::TapHold(
+            rmk::action::Action::Key($k),
+            rmk::action::Action::Modifier($m),
+        )
     };
 }
 
-macro_rules! mo_prog {
-    () => {
-        rmk::mo!(3)
+/// One-shot modifier: applies to the next key when tapped, a normal modifier when held
+macro_rules! osm {
+    ($m: expr) => {
+        rmk::action::KeyAction::Single(rmk::action::Action::OneShotModifier($m))
     };
 }
 
-macro_rules! mo_control {
+/// Types the text macro of `TEXT_MACROS` at `index`, or what Vial changed it to
+macro_rules! text_macro {
+    ($index: expr) => {
+        rmk::action::KeyAction::Single(rmk::action::Action::TriggerMacro($index))
+    };
+}
+
+/// The action of leader sequence `index`, see `LEADER_OUTPUT_POS`
+macro_rules! leader {
+    ($index: expr) => {
+        LEADER_SEQUENCES[$index].1
+    };
+}
+
+/// Tap-dance key, see `TAP_DANCES`
+macro_rules! td {
+    ($index: expr) => {
+        rmk::action::KeyAction::TapDance($index)
+    };
+}
+
+macro_rules! nokey {
     () => {
-        rmk::mo!(1)
+        rmk::action::KeyAction::Single(rmk::action::Action::Key(
+            rmk::keycode::KeyCode::ErrorUndefined,
+        ))
     };
 }
 
-macro_rules! mo_spcl {
+macro_rules! mo_control {
     () => {
-        rmk::mo!(2)
+        rmk::mo!(1)
     };
 }
 
-mod german {
+pub(crate) mod german {
     use rmk::{action::KeyAction, keycode::KeyCode};
 
     pub const Circumflex: KeyCode = KeyCode::Grave;
@@ -154,7 +245,7 @@ mod german {
     // #define DE_GRV  S(DE_ACUT) // ` (dead)
     const GraveAccent: KeyAction = shifted!(Acute);
     // #define DE_ASTR S(DE_PLUS) // *
-    const Asterisk: KeyAction = shifted!(Plus);
+    pub const Asterisk: KeyAction = shifted!(Plus);
     // #define DE_QUOT S(DE_HASH) // '
     const SingleQuote: KeyAction = shifted!(Hash);
     // #define DE_RABK S(DE_LABK) // >
@@ -185,6 +276,242 @@ mod german {
     pub const Pipe: KeyAction = algr!(LeftAngleBracket);
     // #define DE_MICR ALGR(DE_M)    // µ
     const Micro: KeyAction = algr!(KeyCode::M);
+
+    /// Dead keys, they type their character when followed by Space
+    pub const DEAD_KEYS: [char; 3] = ['^', '´', '`'];
+
+    /// The key typing `c` on a German host
+    pub fn char_action(c: char) -> Option<KeyAction> {
+        #[rustfmt::skip]
+        const LETTERS: [KeyCode; 26] = [
+            KeyCode::A, KeyCode::B, KeyCode::C, KeyCode::D, KeyCode::E, KeyCode::F, KeyCode::G,
+            KeyCode::H, KeyCode::I, KeyCode::J, KeyCode::K, KeyCode::L, KeyCode::M, KeyCode::N,
+            KeyCode::O, KeyCode::P, KeyCode::Q, KeyCode::R, KeyCode::S, KeyCode::T, KeyCode::U,
+            KeyCode::V, KeyCode::W, KeyCode::X, Y, Z,
+        ];
+        const DIGITS: [KeyCode; 10] = [Kc0, Kc1, Kc2, Kc3, Kc4, Kc5, Kc6, Kc7, Kc8, Kc9];
+
+        let action = match c {
+            'a'..='z' => k!(LETTERS[c as usize - 'a' as usize]),
+            'A'..='Z' => shifted!(LETTERS[c as usize - 'A' as usize]),
+            '0'..='9' => k!(DIGITS[c as usize - '0' as usize]),
+            'ä' => k!(Adia),
+            'Ä' => shifted!(Adia),
+            'ö' => k!(Odia),
+            'Ö' => shifted!(Odia),
+            'ü' => k!(Udia),
+            'Ü' => shifted!(Udia),
+            'ß' => k!(SharpS),
+            ' ' => k!(KeyCode::Space),
+            '\n' => k!(KeyCode::Enter),
+            '\t' => k!(KeyCode::Tab),
+            '^' => k!(Circumflex),
+            '°' => Degree,
+            '!' => Exclamation,
+            '"' => DoubleQuote,
+            '§' => Section,
+            '$' => Dollar,
+            '%' => Percent,
+            '&' => Ampersand,
+            '/' => Slash,
+            '(' => LeftParenthesis,
+            ')' => RightParenthesis,
+            '=' => Equal,
+            '?' => QuestionMark,
+            '´' => k!(Acute),
+            '`' => GraveAccent,
+            '+' => k!(Plus),
+            '*' => Asterisk,
+            '~' => Tilde,
+            '#' => k!(Hash),
+            '\'' => SingleQuote,
+            '<' => k!(LeftAngleBracket),
+            '>' => RightAngleBracket,
+            '|' => Pipe,
+            ',' => k!(Comma),
+            ';' => Semicolon,
+            '.' => k!(Dot),
+            ':' => Colon,
+            '-' => k!(Minus),
+            '_' => Underscore,
+            '{' => LeftCurlyBracket,
+            '[' => LeftBracket,
+            ']' => RightBracket,
+            '}' => RightCurlyBracket,
+            '\\' => Backslash,
+            '@' => algr!(KeyCode::Q),
+            '€' => algr!(KeyCode::E),
+            '²' => algr!(Kc2),
+            '³' => algr!(Kc3),
+            'µ' => Micro,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// Layout of the host, text macros are typed through it
+pub(crate) use german as host_layout;
+
+/// How the host enters characters its layout has no key for, by their Unicode code point
+#[allow(dead_code)]
+pub(crate) enum UnicodeInput {
+    /// Ctrl+Shift+U, the code point and Space, as IBus and GTK take it
+    Linux,
+    /// Compose (Right Alt), U, the code point and Enter, with WinCompose on Windows
+    WinCompose,
+}
+
+pub(crate) const UNICODE_INPUT: UnicodeInput = UnicodeInput::Linux;
+
+/// Text macros, typed through `host_layout` by the `text_macro!` keys. They are Vial macros, so
+/// Vial's macro editor shows and changes them.
+pub(crate) const TEXT_MACROS: [&str; 3] = [
+    "nio-paws@example.com",
+    "Mit freundlichen Grüßen\n",
+    "fn main() {\n}\n",
+];
+
+/// A leader sequence ends when no key follows within this time
+pub(crate) const LEADER_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Leader sequences, the keys by their key code on the active layer, and the action they trigger
+#[rustfmt::skip]
+pub(crate) const LEADER_SEQUENCES: [(&[KeyCode], KeyAction); 4] = [
+    (&[KeyCode::E, KeyCode::M], text_macro!(0)),
+    (&[KeyCode::G, KeyCode::R], text_macro!(1)),
+    // Task manager
+    (&[KeyCode::T, KeyCode::M], wm!(KeyCode::Escape, ModifierCombination::new_from(false, false, false, true, true))),
+    // Screenshot of a region on Windows
+    (&[KeyCode::S, KeyCode::S], wm!(KeyCode::S, ModifierCombination::new_from(false, true, false, true, false))),
+];
+
+/// Matrix positions `(row, col)` without a switch, with the action of the leader sequence of the
+/// same index on BASE and transparent above. The firmware taps them when a sequence matches, so
+/// rmk triggers the action.
+pub(crate) const LEADER_OUTPUT_POS: [(u8, u8); LEADER_SEQUENCES.len()] =
+    [(4, 1), (4, 2), (4, 3), (4, 4)];
+
+/// Keys pressed together within this time are a combo
+const COMBO_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Combos of two or three keys by matrix position `(row, col)`, and the action they trigger. With
+/// a layer they only trigger while it is the active one.
+/// They leave out the home-row mods, a combo would swallow a quick roll over them.
+#[rustfmt::skip]
+const COMBOS: [(&[(usize, usize)], KeyAction, Option<u8>); 8] = [
+    (&[(2, 3), (2, 4)],   german::LeftCurlyBracket,  None), // C+V
+    (&[(2, 11), (2, 12)], german::RightCurlyBracket, None), // M+Comma
+    (&[(2, 2), (2, 3)],   german::LeftBracket,       None), // X+C
+    (&[(2, 12), (2, 13)], german::RightBracket,      None), // Comma+Dot
+    (&[(0, 3), (0, 4)],   german::LeftParenthesis,   None), // E+R
+    (&[(0, 11), (0, 12)], german::RightParenthesis,  None), // U+I
+    (&[(0, 2), (0, 3)],   german::Pipe,              None), // W+E
+    (&[(0, 12), (0, 13)], german::Backslash,         None), // I+O
+];
+
+/// Combos of `keymap`. rmk matches combos by the actions of the keys, so the positions are looked
+/// up in the combo's layer, or BASE. Combos with keys outside of the keymap are left out.
+pub(crate) fn get_combos_config<const ROW: usize, const COL: usize>(
+    keymap: &[[[KeyAction; COL]; ROW]; NUM_LAYER],
+) -> CombosConfig {
+    let mut combos = Vec::new();
+    for (positions, output, layer) in COMBOS {
+        if positions.iter().any(|&(row, col)| row >= ROW || col >= COL) {
+            continue;
+        }
+        let layer_keys = &keymap[layer.unwrap_or(0) as usize];
+        let actions = positions.iter().map(|&(row, col)| layer_keys[row][col]);
+        // rmk has room for all of `COMBOS`
+        let _ = combos.push(Combo::new(actions, output, layer));
+    }
+    CombosConfig {
+        combos,
+        timeout: COMBO_TIMEOUT,
+    }
+}
+
+/// Tap-dance keys, indices into `TAP_DANCES`
+const TD_SPACE_SPCL: u8 = 0;
+const TD_TAB_CONTROL: u8 = 1;
+const TD_BACKSPACE_PROG: u8 = 2;
+const TD_SHIFT: u8 = 3;
+const TD_ONE_SHOT_SPCL: u8 = 4;
+const TD_ONE_SHOT_PROG: u8 = 5;
+
+/// Taps of a tap-dance key closer than this count as a double tap
+pub(crate) const TAP_DANCE_TERM: Duration = Duration::from_millis(200);
+
+/// Actions of the tap-dance keys: tapped, held, tapped then held and double tapped
+#[rustfmt::skip]
+const TAP_DANCES: [(Action, Action, Action, Action); 6] = [
+    // SPCL when held, Enter when double tapped
+    (Action::Key(KeyCode::Space),     Action::LayerOn(SPCL),    Action::Key(KeyCode::Space),     Action::Key(KeyCode::Enter)),
+    // CONTROL when held, Escape when double tapped
+    (Action::Key(KeyCode::Tab),       Action::LayerOn(CONTROL), Action::LayerOn(CONTROL),        Action::Key(KeyCode::Escape)),
+    // PROG when held, Delete when double tapped
+    (Action::Key(KeyCode::Backspace), Action::LayerOn(PROG),    Action::Key(KeyCode::Backspace), Action::Key(KeyCode::Delete)),
+    // One-shot Shift, Caps Lock when double tapped
+    (Action::OneShotModifier(SHIFT),  Action::Modifier(SHIFT),  Action::Modifier(SHIFT),         Action::Key(KeyCode::CapsLock)),
+    // One-shot layers, locked when double tapped and unlocked by another double tap
+    (Action::OneShotLayer(SPCL),      Action::LayerOn(SPCL),    Action::LayerOn(SPCL),           Action::LayerToggle(SPCL)),
+    (Action::OneShotLayer(PROG),      Action::LayerOn(PROG),    Action::LayerOn(PROG),           Action::LayerToggle(PROG)),
+];
+
+/// One-shot modifiers and layers apply to the next key pressed within this time
+const ONE_SHOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) fn get_one_shot_config() -> OneShotConfig {
+    OneShotConfig {
+        timeout: ONE_SHOT_TIMEOUT,
+    }
+}
+
+/// Key overrides: the key pressed with any of the modifiers triggers the replacement instead. The
+/// suppressed modifiers are released for it, the others stay.
+#[rustfmt::skip]
+const KEY_OVERRIDES: [(KeyAction, ModifierCombination, KeyAction, ModifierCombination); 2] = [
+    (k!(KeyCode::Backspace), SHIFT, k!(KeyCode::Delete), SHIFT),
+    (k!(german::Plus),       SHIFT, german::Asterisk,    SHIFT),
+];
+
+/// rmk's forks implement the key overrides
+pub(crate) fn get_forks_config() -> ForksConfig {
+    let mut forks = Vec::new();
+    for (trigger, modifiers, replacement, suppressed) in KEY_OVERRIDES {
+        let modifiers = modifiers.to_hid_modifiers();
+        let kept = modifiers.into_bits() & !suppressed.to_hid_modifiers().into_bits();
+        let match_any = StateBits {
+            modifiers,
+            ..Default::default()
+        };
+        // rmk has room for all of `KEY_OVERRIDES`
+        let _ = forks.push(Fork::new(
+            trigger,
+            trigger,
+            replacement,
+            match_any,
+            StateBits::default(),
+            HidModifiers::from_bits(kept),
+            false,
+        ));
+    }
+    ForksConfig { forks }
+}
+
+pub(crate) fn get_tap_dances_config() -> TapDancesConfig {
+    let mut tap_dances = Vec::new();
+    for (tap, hold, hold_after_tap, double_tap) in TAP_DANCES {
+        // rmk has room for all of `TAP_DANCES`
+        let _ = tap_dances.push(TapDance::new_from_vial(
+            tap,
+            hold,
+            hold_after_tap,
+            double_tap,
+            TAP_DANCE_TERM,
+        ));
+    }
+    TapDancesConfig { tap_dances }
 }
 
 #[rustfmt::skip]
@@ -195,19 +522,19 @@ pub const fn get_default_keymap() -> [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_L
     [
         //BASE
         layer!([
-            [k!(Backspace), k!(Delete), k!(W),     k!(E),    k!(R),    k!(T),      a!(No),        nokey!(), /**/ nokey!(),  k!(Kc0),       k!(g::Z),   k!(U),    k!(I),        k!(O),       a!(No),       a!(No)],
-            [k!(Escape),    k!(Q),      k!(S),     k!(D),    k!(F),    k!(G),      mo_control!(), nokey!(), /**/ nokey!(),  a!(No),        k!(H),      k!(J),    k!(K),        k!(L),       k!(P),        a!(No)],
-            [k!(LShift),    k!(A),      k!(X),     k!(C),    k!(V),    k!(B),      nokey!(),      nokey!(), /**/ nokey!(),  nokey!(),      k!(N),      k!(M),    k!(g::Comma), k!(g::Dot),  k!(Enter),    k!(Tab)],
-            [nokey!(),      k!(g::Y),   nokey!(),  nokey!(), k!(LGui), mo_prog!(), k!(Space),     k!(LAlt), /**/ a!(No),    mo_control!(), mo_spcl!(), a!(No),   nokey!(),     nokey!(),    k!(g::Minus), nokey!()],
-            [nokey!(),      nokey!(),   nokey!(),  nokey!(), nokey!(), nokey!(),   k!(LCtrl),     k!(RAlt), /**/ a!(No),    a!(No),        nokey!(),   nokey!(), nokey!(),     nokey!(),    nokey!(),     nokey!()]
+            [k!(Backspace), k!(Delete),   k!(W),        k!(E),         k!(R),          k!(T),                  a!(No),        nokey!(),  /**/ nokey!(),              k!(Kc0),             k!(g::Z),           k!(U),                 k!(I),         k!(O),        a!(No),       a!(No)],
+            [k!(Escape),    k!(Q),        hrm!(S, ALT), hrm!(D, CTRL), hrm!(F, SHIFT), k!(G),                  mo_control!(), nokey!(),  /**/ nokey!(),              a!(No),              k!(H),              hrm!(J, SHIFT),        hrm!(K, CTRL), hrm!(L, ALT), hrm!(P, GUI), a!(No)],
+            [td!(TD_SHIFT), hrm!(A, GUI), k!(X),        k!(C),         k!(V),          k!(B),                  nokey!(),      nokey!(),  /**/ nokey!(),              nokey!(),            k!(N),              k!(M),                 k!(g::Comma),  k!(g::Dot),   k!(Enter),    k!(Tab)],
+            [nokey!(),      k!(g::Y),     nokey!(),     nokey!(),      osm!(GUI),      td!(TD_BACKSPACE_PROG), k!(Space),     osm!(ALT), /**/ td!(TD_ONE_SHOT_PROG), td!(TD_TAB_CONTROL), td!(TD_SPACE_SPCL), td!(TD_ONE_SHOT_SPCL), nokey!(),      nokey!(),     k!(g::Minus), nokey!()],
+            [k!(LShift),    leader!(0),   leader!(1),   leader!(2),    leader!(3),     nokey!(),               osm!(CTRL),    k!(RAlt),  /**/ a!(No),                k!(LEADER_KEY),      nokey!(),           nokey!(),              nokey!(),      nokey!(),     nokey!(),     nokey!()]
         ]),
         //CONTROL
         layer!([
-            [a!(Transparent), a!(Transparent), k!(F2),           k!(F3),          k!(F4),          k!(F5),          a!(Transparent),   nokey!(),        /**/ nokey!(),        k!(Kc1),         a!(Transparent), a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent)],
-            [a!(Transparent), k!(F1),          k!(PrintScreen),  a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),   nokey!(),        /**/ nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent)],
-            [a!(Transparent), a!(Transparent), a!(Transparent),  a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),          nokey!(),        /**/ nokey!(),        nokey!(),        a!(Transparent), a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent),   a!(Transparent)],
-            [nokey!(),        a!(Transparent), nokey!(),         nokey!(),        a!(Transparent), k!(Insert),      a!(Transparent),   a!(Transparent), /**/ a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),   nokey!(),          nokey!(),          a!(Transparent),   nokey!()],
-            [nokey!(),        nokey!(),        nokey!(),         nokey!(),        nokey!(),        nokey!(),        a!(Transparent),   nokey!(),        /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),          nokey!(),          nokey!(),          nokey!(),          nokey!()]
+            [a!(Transparent), k!(CAPS_WORD),    k!(F2),           k!(F3),          k!(F4),          k!(F5),          a!(Transparent), nokey!(),        /**/ nokey!(),        k!(Kc1),         a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [a!(Transparent), k!(F1),           k!(PrintScreen),  a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        /**/ nokey!(),        a!(Transparent), text_macro!(0),  text_macro!(1),  text_macro!(2),  a!(Transparent), a!(Transparent), k!(DFU)],
+            [a!(Transparent), k!(DM_RECORD[0]), k!(DM_RECORD[1]), k!(DM_STOP),     k!(DM_PLAY[0]),  k!(DM_PLAY[1]),  nokey!(),        nokey!(),        /**/ nokey!(),        nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [nokey!(),        a!(Transparent),  nokey!(),         nokey!(),        a!(Transparent), k!(Insert),      a!(Transparent), a!(Transparent), /**/ a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        a!(Transparent), nokey!()],
+            [a!(Transparent), a!(Transparent),  a!(Transparent),  a!(Transparent), a!(Transparent), nokey!(),        a!(Transparent), nokey!(),        /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!()]
         ]),
         //SPCL
         layer!([
@@ -215,7 +542,7 @@ pub const fn get_default_keymap() -> [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_L
             [a!(Transparent),   k!(g::Kc1),        k!(Backspace),   k!(g::Udia),     k!(g::Odia),     k!(Delete),      a!(Transparent), nokey!(),          /**/ nokey!(),        a!(Transparent), k!(Left),        k!(Down),        k!(Up),          k!(Right),       k!(g::Kc0),      k!(g::Acute)],
             [a!(Transparent),   k!(g::Adia),       a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        nokey!(),          /**/ nokey!(),        nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
             [nokey!(),          k!(g::Circumflex), nokey!(),        nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),   /**/ a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        a!(Transparent), nokey!()],
-            [nokey!(),          nokey!(),          nokey!(),        nokey!(),        nokey!(),        nokey!(),        a!(Transparent), a!(Transparent),   /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!()]
+            [a!(Transparent),   a!(Transparent),   a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        a!(Transparent), a!(Transparent),   /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!()]
         ]),
         //PROG
         layer!([
@@ -223,7 +550,140 @@ pub const fn get_default_keymap() -> [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_L
             [k!(g::Circumflex), g::Exclamation,  k!(g::LeftAngleBracket), g::RightAngleBracket, k!(g::Plus),     k!(g::Hash),     a!(Transparent),   nokey!(),        /**/ nokey!(),        a!(Transparent), g::Slash,        a!(Transparent),      g::LeftParenthesis, g::RightParenthesis, g::RightCurlyBracket, a!(Transparent)],
             [a!(Transparent),   a!(Transparent), a!(Transparent),         a!(Transparent),      a!(Transparent), a!(Transparent), nokey!(),          nokey!(),        /**/ nokey!(),        nokey!(),        a!(Transparent), a!(Transparent),      a!(Transparent),    a!(Transparent),     g::Equal,             a!(Transparent)],
             [nokey!(),          a!(Transparent), nokey!(),                nokey!(),             a!(Transparent), a!(Transparent), a!(Transparent),   a!(Transparent), /**/ a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),      nokey!(),           nokey!(),            a!(Transparent),      nokey!()],
-            [nokey!(),          nokey!(),        nokey!(),                nokey!(),             nokey!(),        nokey!(),        a!(Transparent),   a!(Transparent), /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),             nokey!(),           nokey!(),            nokey!(),             nokey!()]
+            [a!(Transparent),   a!(Transparent), a!(Transparent),         a!(Transparent),      a!(Transparent), nokey!(),        a!(Transparent),   a!(Transparent), /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),             nokey!(),           nokey!(),            nokey!(),             nokey!()]
+        ]),
+        //ADJUST
+        layer!([
+            [a!(Transparent), a!(Transparent), k!(DFU),         a!(Transparent),       k!(RESET_STORAGE),   a!(Transparent), a!(Transparent), nokey!(),        /**/ nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [a!(Transparent), a!(Transparent), a!(Transparent), k!(TAPPING_TERM_DOWN), k!(TAPPING_TERM_UP), a!(Transparent), a!(Transparent), nokey!(),        /**/ nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),       a!(Transparent),     a!(Transparent), nokey!(),        nokey!(),        /**/ nokey!(),        nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [nokey!(),        a!(Transparent), nokey!(),        nokey!(),              a!(Transparent),     a!(Transparent), a!(Transparent), a!(Transparent), /**/ a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        a!(Transparent), nokey!()],
+            [a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),       a!(Transparent),     nokey!(),        a!(Transparent), a!(Transparent), /**/ a!(Transparent), a!(Transparent), nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!(),        nokey!()]
+        ]),
+    ]
+}
+
+/// Keymap of the left half running alone. The right thumb keys are missing, so SPCL moves to the
+/// left thumb and the arrows to SPCL.
+#[cfg(feature = "standalone")]
+#[rustfmt::skip]
+pub const fn get_standalone_keymap() -> [[[KeyAction; LEFT_COL]; LEFT_ROW]; NUM_LAYER] {
+    use rmk::keycode::KeyCode::*;
+    use german as g;
+
+    [
+        //BASE
+        layer!([
+            [k!(Backspace), k!(Delete),   k!(W),        k!(E),         k!(R),          k!(T),                  td!(TD_ONE_SHOT_PROG), nokey!()],
+            [k!(Escape),    k!(Q),        hrm!(S, ALT), hrm!(D, CTRL), hrm!(F, SHIFT), k!(G),                  mo_control!(),         nokey!()],
+            [td!(TD_SHIFT), hrm!(A, GUI), k!(X),        k!(C),         k!(V),          k!(B),                  nokey!(),              nokey!()],
+            [nokey!(),      k!(g::Y),     nokey!(),     nokey!(),      osm!(GUI),      td!(TD_BACKSPACE_PROG), k!(Space),             td!(TD_SPACE_SPCL)],
+            [k!(LShift),    leader!(0),   leader!(1),   leader!(2),    leader!(3),     nokey!(),               osm!(CTRL),            osm!(ALT)]
+        ]),
+        //CONTROL
+        layer!([
+            [a!(Transparent), k!(CAPS_WORD),    k!(F2),           k!(F3),          k!(F4),          k!(F5),          k!(DFU),         nokey!()],
+            [a!(Transparent), k!(F1),           k!(PrintScreen),  text_macro!(0),  text_macro!(1),  text_macro!(2),  a!(Transparent), nokey!()],
+            [a!(Transparent), k!(DM_RECORD[0]), k!(DM_RECORD[1]), k!(DM_STOP),     k!(DM_PLAY[0]),  k!(DM_PLAY[1]),  nokey!(),        nokey!()],
+            [nokey!(),        a!(Transparent),  nokey!(),         nokey!(),        a!(Transparent), k!(Insert),      a!(Transparent), a!(Transparent)],
+            [a!(Transparent), a!(Transparent),  a!(Transparent),  a!(Transparent), a!(Transparent), nokey!(),        a!(Transparent), k!(RAlt)]
+        ]),
+        //SPCL
+        layer!([
+            [a!(Transparent),   a!(Transparent),   k!(g::Kc2),      k!(g::Kc3),      k!(g::Kc4),      k!(g::Kc5),      a!(Transparent), nokey!()],
+            [a!(Transparent),   k!(g::Kc1),        k!(Backspace),   k!(g::Udia),     k!(g::Odia),     k!(Delete),      a!(Transparent), nokey!()],
+            [a!(Transparent),   k!(g::Adia),       k!(Left),        k!(Down),        k!(Up),          k!(Right),       nokey!(),        nokey!()],
+            [nokey!(),          k!(g::Circumflex), nokey!(),        nokey!(),        a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent)],
+            [a!(Transparent),   a!(Transparent),   a!(Transparent), a!(Transparent), a!(Transparent), nokey!(),        a!(Transparent), a!(Transparent)]
+        ]),
+        //PROG
+        layer!([
+            [a!(Transparent),   a!(Transparent), g::DoubleQuote,          a!(Transparent),      g::Dollar,       g::Tilde,        a!(Transparent),   nokey!()],
+            [k!(g::Circumflex), g::Exclamation,  k!(g::LeftAngleBracket), g::RightAngleBracket, k!(g::Plus),     k!(g::Hash),     a!(Transparent),   nokey!()],
+            [a!(Transparent),   a!(Transparent), a!(Transparent),         a!(Transparent),      a!(Transparent), a!(Transparent), nokey!(),          nokey!()],
+            [nokey!(),          a!(Transparent), nokey!(),                nokey!(),             a!(Transparent), a!(Transparent), a!(Transparent),   a!(Transparent)],
+            [a!(Transparent),   a!(Transparent), a!(Transparent),         a!(Transparent),      a!(Transparent), nokey!(),        a!(Transparent),   a!(Transparent)]
+        ]),
+        //ADJUST
+        layer!([
+            [a!(Transparent), a!(Transparent), k!(DFU),         a!(Transparent),       k!(RESET_STORAGE),   a!(Transparent), a!(Transparent), nokey!()],
+            [a!(Transparent), a!(Transparent), a!(Transparent), k!(TAPPING_TERM_DOWN), k!(TAPPING_TERM_UP), a!(Transparent), a!(Transparent), nokey!()],
+            [a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),       a!(Transparent),     a!(Transparent), nokey!(),        nokey!()],
+            [nokey!(),        a!(Transparent), nokey!(),        nokey!(),              a!(Transparent),     a!(Transparent), a!(Transparent), a!(Transparent)],
+            [a!(Transparent), a!(Transparent), a!(Transparent), a!(Transparent),       a!(Transparent),     nokey!(),        a!(Transparent), a!(Transparent)]
         ]),
     ]
 }
+
+/// Firmware keys of features the RP2040 halves don't have
+#[cfg(feature = "rp2040")]
+const STM32_ONLY: [KeyCode; 9] = [
+    DFU,
+    RESET_STORAGE,
+    TAPPING_TERM_UP,
+    TAPPING_TERM_DOWN,
+    DM_RECORD[0],
+    DM_RECORD[1],
+    DM_STOP,
+    DM_PLAY[0],
+    DM_PLAY[1],
+];
+
+/// The keymap of the RP2040 central: the default keymap without the keys of `STM32_ONLY`
+#[cfg(feature = "rp2040")]
+pub const fn get_rp2040_keymap() -> [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_LAYER] {
+    let mut keymap = get_default_keymap();
+    let mut layer = 0;
+    while layer < NUM_LAYER {
+        let mut row = 0;
+        while row < TOTAL_ROW {
+            let mut col = 0;
+            while col < TOTAL_COL {
+                if let KeyAction::Single(Action::Key(key)) = keymap[layer][row][col] {
+                    let mut i = 0;
+                    while i < STM32_ONLY.len() {
+                        // `==` isn't const
+                        if STM32_ONLY[i] as u16 == key as u16 {
+                            keymap[layer][row][col] = KeyAction::No;
+                        }
+                        i += 1;
+                    }
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        layer += 1;
+    }
+    keymap
+}
+
+/// The keymap as compiled, for the firmware to tell what a key does before rmk acts on it. Keys
+/// changed in Vial keep their compiled action here.
+#[cfg(not(any(feature = "standalone", feature = "rp2040")))]
+static KEYMAP: [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_LAYER] = get_default_keymap();
+#[cfg(feature = "rp2040")]
+static KEYMAP: [[[KeyAction; TOTAL_COL]; TOTAL_ROW]; NUM_LAYER] = get_rp2040_keymap();
+#[cfg(feature = "standalone")]
+static KEYMAP: [[[KeyAction; LEFT_COL]; LEFT_ROW]; NUM_LAYER] = get_standalone_keymap();
+
+/// The action of the key at `(row, col)` on `layer`, transparent keys fall through to BASE
+pub(crate) fn action_at(layer: u8, row: u8, col: u8) -> KeyAction {
+    let at = |layer: usize| {
+        KEYMAP
+            .get(layer)
+            .and_then(|keys| keys.get(row as usize))
+            .and_then(|keys| keys.get(col as usize))
+            .copied()
+            .unwrap_or(KeyAction::No)
+    };
+    match at(layer as usize) {
+        KeyAction::Transparent => at(0),
+        action => action,
+    }
+}
+
+/// Whether `key` is one of the dynamic macro keys, which macros don't record
+pub(crate) fn is_dm_key(key: KeyCode) -> bool {
+    key == DM_STOP || DM_RECORD.contains(&key) || DM_PLAY.contains(&key)
+}
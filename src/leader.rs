@@ -0,0 +1,143 @@
+//! Leader key: starts a sequence of keys, each within `LEADER_TIMEOUT` of the last, that triggers
+//! the action `LEADER_SEQUENCES` declares for it.
+//!
+//! `key_filter` hands the key events to `Leader`. The Leader key and the keys of a sequence don't
+//! reach rmk, layer keys do, and the keys are told apart by their key codes on the active layer.
+//! When a sequence matches, the filter taps its position of `LEADER_OUTPUT_POS`, so rmk triggers
+//! the action like any key's.
+
+use defmt::{info, warn};
+use embassy_time::Instant;
+use heapless::Vec;
+use rmk::action::{Action, KeyAction};
+use rmk::keycode::KeyCode;
+
+use crate::key_filter::key_code;
+use crate::keymap::{LEADER_KEY, LEADER_OUTPUT_POS, LEADER_SEQUENCES, LEADER_TIMEOUT};
+
+/// Longer sequences match none
+const MAX_SEQUENCE_LEN: usize = 4;
+
+/// What becomes of a key event
+pub(crate) enum Outcome {
+    /// rmk gets it
+    Pass,
+    /// Part of a sequence, rmk doesn't get it
+    Swallowed,
+    /// Matched a sequence, rmk gets a tap of the position with its action instead
+    Matched((u8, u8)),
+}
+
+pub(crate) struct Leader {
+    /// The keys of the running sequence, if there is one
+    sequence: Option<Vec<KeyCode, MAX_SEQUENCE_LEN>>,
+    last_key: Instant,
+    /// Keys pressed in a sequence, their releases are swallowed too
+    swallowed: Vec<(u8, u8), 8>,
+}
+
+impl Leader {
+    pub(crate) fn new() -> Self {
+        Self {
+            sequence: None,
+            last_key: Instant::now(),
+            swallowed: Vec::new(),
+        }
+    }
+
+    /// When the sequence ends if no key follows
+    pub(crate) fn timeout(&self) -> Option<Instant> {
+        self.sequence
+            .as_ref()
+            .map(|_| self.last_key + LEADER_TIMEOUT)
+    }
+
+    /// End the sequence after `timeout`, with the position of the sequence it matches
+    pub(crate) fn time_out(&mut self) -> Option<(u8, u8)> {
+        let sequence = self.sequence.take()?;
+        let matched = LEADER_SEQUENCES
+            .iter()
+            .position(|(keys, _)| *keys == sequence.as_slice());
+        if matched.is_none() {
+            info!("No leader sequence matches");
+        }
+        matched.map(|index| LEADER_OUTPUT_POS[index])
+    }
+
+    pub(crate) fn cancel(&mut self) {
+        if self.sequence.take().is_some() {
+            info!("Leader sequence cancelled");
+        }
+    }
+
+    /// Whether the release of the key at `pos` is swallowed
+    pub(crate) fn swallows(&self, pos: (u8, u8)) -> bool {
+        self.swallowed.contains(&pos)
+    }
+
+    /// What becomes of the event of the key at `pos` with `action`
+    pub(crate) fn key(&mut self, pos: (u8, u8), pressed: bool, action: KeyAction) -> Outcome {
+        if !pressed {
+            let Some(index) = self.swallowed.iter().position(|&key| key == pos) else {
+                return Outcome::Pass;
+            };
+            self.swallowed.swap_remove(index);
+            return self.released();
+        }
+
+        let is_leader = key_code(action) == Some(LEADER_KEY);
+        let Some(sequence) = &mut self.sequence else {
+            if !is_leader {
+                return Outcome::Pass;
+            }
+            info!("Leader sequence");
+            self.sequence = Some(Vec::new());
+            self.last_key = Instant::now();
+            self.swallow(pos);
+            return Outcome::Swallowed;
+        };
+        // The keys of a sequence may be on another layer
+        if let KeyAction::Single(Action::LayerOn(_)) = action {
+            return Outcome::Pass;
+        }
+        self.last_key = Instant::now();
+        if is_leader {
+            info!("Leader sequence cancelled");
+            self.sequence = None;
+        } else if !key_code(action).is_none_or(|key| sequence.push(key).is_ok()) {
+            info!("No leader sequence matches");
+            self.sequence = None;
+        }
+        self.swallow(pos);
+        Outcome::Swallowed
+    }
+
+    /// Decided once a key is released, so the match doesn't wait for the timeout
+    fn released(&mut self) -> Outcome {
+        let Some(sequence) = &self.sequence else {
+            return Outcome::Swallowed;
+        };
+        let mut candidates = LEADER_SEQUENCES
+            .iter()
+            .enumerate()
+            .filter(|(_, (keys, _))| keys.starts_with(sequence));
+        match (candidates.next(), candidates.next()) {
+            (None, _) => {
+                info!("No leader sequence matches");
+                self.sequence = None;
+                Outcome::Swallowed
+            }
+            (Some((index, (keys, _))), None) if keys.len() == sequence.len() => {
+                self.sequence = None;
+                Outcome::Matched(LEADER_OUTPUT_POS[index])
+            }
+            _ => Outcome::Swallowed,
+        }
+    }
+
+    fn swallow(&mut self, pos: (u8, u8)) {
+        if self.swallowed.push(pos).is_err() {
+            warn!("Too many keys held in a leader sequence");
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Control messages of the peripheral, handled on the central.
+
+use core::cell::RefCell;
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "ota")]
+use embassy_sync::channel::Channel;
+use heapless::String;
+
+use crate::build_info::{BUILD_INFO, BUILD_INFO_LEN, ClockSource};
+use crate::split_link::{LINK_INBOX, LINK_OUTBOX, LinkMessage};
+
+/// Build info and clock source of the peripheral, once it sent them
+static PERIPHERAL_BUILD_INFO: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Option<(String<BUILD_INFO_LEN>, ClockSource)>>,
+> = Mutex::new(RefCell::new(None));
+
+/// Answers of the peripheral to the updater
+#[cfg(feature = "ota")]
+pub(crate) static UPDATE_REPLIES: Channel<CriticalSectionRawMutex, LinkMessage, 2> = Channel::new();
+
+/// Build info and clock source of the peripheral. Asks the peripheral for them while they aren't
+/// known yet.
+pub(crate) fn peripheral_build_info() -> Option<(String<BUILD_INFO_LEN>, ClockSource)> {
+    let build_info = PERIPHERAL_BUILD_INFO.lock(|build_info| build_info.borrow().clone());
+    if build_info.is_none() {
+        // A request is already queued if this fails
+        let _ = LINK_OUTBOX.try_send(LinkMessage::BuildInfoRequest);
+    }
+    build_info
+}
+
+pub(crate) async fn run_link_messages() {
+    // The peripheral sends its build info when it boots, but it may have booted first
+    LINK_OUTBOX.send(LinkMessage::BuildInfoRequest).await;
+    loop {
+        match LINK_INBOX.receive().await {
+            LinkMessage::BuildInfo {
+                build_info,
+                clock_source,
+            } => {
+                info!(
+                    "Peripheral firmware {}, clock source: {}",
+                    build_info, clock_source
+                );
+                if build_info != BUILD_INFO {
+                    warn!("The halves run different firmware builds");
+                }
+                if clock_source == ClockSource::HsiFallback {
+                    warn!("The peripheral's HSE didn't start, it runs from the HSI");
+                }
+                PERIPHERAL_BUILD_INFO.lock(|cell| cell.replace(Some((build_info, clock_source))));
+                #[cfg(feature = "ota")]
+                crate::updater::healthy();
+            }
+            #[cfg(feature = "ota")]
+            message @ (LinkMessage::UpdateAck { .. }
+            | LinkMessage::UpdateDone
+            | LinkMessage::UpdateFailed) => UPDATE_REPLIES.send(message).await,
+            message => warn!("Unexpected link message {}", message),
+        }
+    }
+}
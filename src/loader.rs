@@ -0,0 +1,206 @@
+//! Loader in front of the firmware when it is built with the `ota` feature.
+//!
+//! Installs an update the firmware left for it and starts the firmware. Images are copied rather
+//! than swapped, their source stays intact until the state moves on, so an interrupted copy starts
+//! over on the next boot.
+//!
+//! The peripheral's image comes from the upper half of its own flash. The central's comes from its
+//! W25Q, where the running firmware is backed up first. The updated central runs under the
+//! watchdog until it confirms it's healthy, if it resets before that the backup is restored.
+
+#![no_main]
+#![no_std]
+
+mod boot_state;
+mod crc;
+mod partitions;
+mod spi_flash;
+
+use boot_state::{BootState, WATCHDOG_TIMEOUT_US};
+use cortex_m::peripheral::{NVIC, SCB};
+use cortex_m_rt::entry;
+use crc::Crc32;
+use defmt::{Format, info, warn};
+use embassy_stm32::flash::{self, Blocking, FLASH_BASE, Flash};
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::spi::{self, Spi};
+use embassy_stm32::wdg::IndependentWatchdog;
+use partitions::{
+    ACTIVE_OFFSET, CENTRAL_ACTIVE_SIZE, CENTRAL_BACKUP_OFFSET, CENTRAL_STAGING_OFFSET,
+    PERIPHERAL_ACTIVE_SIZE, PERIPHERAL_DFU_OFFSET,
+};
+use spi_flash::{PAGE_SIZE, SECTOR_SIZE, SpiFlash};
+
+use {defmt_rtt as _, panic_probe as _};
+
+#[derive(Format)]
+enum InstallError {
+    Flash(flash::Error),
+    Spi(spi::Error),
+    /// The copy doesn't match the image
+    Verify,
+}
+
+impl From<flash::Error> for InstallError {
+    fn from(e: flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+impl From<spi::Error> for InstallError {
+    fn from(e: spi::Error) -> Self {
+        Self::Spi(e)
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_stm32::init(Default::default());
+    let mut flash = Flash::new_blocking(p.FLASH);
+
+    let Some(state) = BootState::read(&mut flash) else {
+        unsafe { start_firmware(FLASH_BASE as u32 + ACTIVE_OFFSET) }
+    };
+    info!("Boot state {}", state);
+
+    // Same wiring as the central: SCK A5, MISO A6, MOSI A7, select A4
+    let spi_flash = || {
+        let spi = Spi::new_blocking(p.SPI1, p.PA5, p.PA7, p.PA6, spi::Config::default());
+        SpiFlash::new(spi, Output::new(p.PA4, Level::High, Speed::Medium))
+    };
+    let result = match state {
+        BootState::InstallInternal { size, crc } => install_internal(&mut flash, size, crc),
+        BootState::InstallExternal { size, crc } => {
+            install_external(&mut flash, &mut spi_flash(), size, crc, true)
+        }
+        BootState::Swapping { size, crc } => {
+            install_external(&mut flash, &mut spi_flash(), size, crc, false)
+        }
+        BootState::Testing => rollback(&mut flash, &mut spi_flash()),
+    };
+    if let Err(e) = result {
+        // The firmware may be half written, try again
+        warn!("Failed to install update: {}", e);
+        SCB::sys_reset()
+    }
+
+    if BootState::read(&mut flash) == Some(BootState::Testing) {
+        info!("Starting the update under the watchdog");
+        IndependentWatchdog::new(p.IWDG, WATCHDOG_TIMEOUT_US).unleash();
+    }
+    unsafe { start_firmware(FLASH_BASE as u32 + ACTIVE_OFFSET) }
+}
+
+fn install_internal(
+    flash: &mut Flash<'_, Blocking>,
+    size: u32,
+    crc: u32,
+) -> Result<(), InstallError> {
+    if size > PERIPHERAL_ACTIVE_SIZE
+        || boot_state::flash_crc(flash, PERIPHERAL_DFU_OFFSET, size)? != crc
+    {
+        warn!("Update is corrupt, keeping the firmware");
+        return Ok(boot_state::clear(flash)?);
+    }
+
+    flash.blocking_erase(ACTIVE_OFFSET, ACTIVE_OFFSET + PERIPHERAL_ACTIVE_SIZE)?;
+    let mut buf = [0; PAGE_SIZE as usize];
+    for offset in (0..size).step_by(buf.len()) {
+        flash.blocking_read(PERIPHERAL_DFU_OFFSET + offset, &mut buf)?;
+        flash.blocking_write(ACTIVE_OFFSET + offset, &buf)?;
+    }
+    if boot_state::flash_crc(flash, ACTIVE_OFFSET, size)? != crc {
+        return Err(InstallError::Verify);
+    }
+    info!("Installed update");
+    Ok(boot_state::clear(flash)?)
+}
+
+/// Install the central's image, backing up the firmware first unless that is already done.
+fn install_external(
+    flash: &mut Flash<'_, Blocking>,
+    spi_flash: &mut SpiFlash<'_>,
+    size: u32,
+    crc: u32,
+    backup: bool,
+) -> Result<(), InstallError> {
+    if backup {
+        if size > CENTRAL_ACTIVE_SIZE || external_crc(spi_flash, size)? != crc {
+            warn!("Update is corrupt, keeping the firmware");
+            return Ok(boot_state::clear(flash)?);
+        }
+        info!("Backing up the firmware");
+        let mut buf = [0; PAGE_SIZE as usize];
+        for offset in (0..CENTRAL_ACTIVE_SIZE).step_by(buf.len()) {
+            if offset % SECTOR_SIZE == 0 {
+                spi_flash.erase_sector(CENTRAL_BACKUP_OFFSET + offset)?;
+            }
+            flash.blocking_read(ACTIVE_OFFSET + offset, &mut buf)?;
+            spi_flash.program_page(CENTRAL_BACKUP_OFFSET + offset, &buf)?;
+        }
+        BootState::Swapping { size, crc }.write(flash)?;
+    }
+
+    copy_to_active(flash, spi_flash, CENTRAL_STAGING_OFFSET, size)?;
+    if boot_state::flash_crc(flash, ACTIVE_OFFSET, size)? != crc {
+        warn!("Installed update doesn't verify, rolling back");
+        return rollback(flash, spi_flash);
+    }
+    info!("Installed update");
+    Ok(BootState::Testing.write(flash)?)
+}
+
+/// Restore the firmware backed up before the update.
+fn rollback(
+    flash: &mut Flash<'_, Blocking>,
+    spi_flash: &mut SpiFlash<'_>,
+) -> Result<(), InstallError> {
+    warn!("The update didn't confirm, restoring the previous firmware");
+    copy_to_active(flash, spi_flash, CENTRAL_BACKUP_OFFSET, CENTRAL_ACTIVE_SIZE)?;
+    Ok(boot_state::clear(flash)?)
+}
+
+/// Replace the firmware of the central with `size` bytes of the W25Q at `from`.
+fn copy_to_active(
+    flash: &mut Flash<'_, Blocking>,
+    spi_flash: &mut SpiFlash<'_>,
+    from: u32,
+    size: u32,
+) -> Result<(), InstallError> {
+    flash.blocking_erase(ACTIVE_OFFSET, ACTIVE_OFFSET + CENTRAL_ACTIVE_SIZE)?;
+    let mut buf = [0; PAGE_SIZE as usize];
+    for offset in (0..size).step_by(buf.len()) {
+        spi_flash.read(from + offset, &mut buf)?;
+        flash.blocking_write(ACTIVE_OFFSET + offset, &buf)?;
+    }
+    Ok(())
+}
+
+/// CRC of the central's image staged in the W25Q
+fn external_crc(spi_flash: &mut SpiFlash<'_>, size: u32) -> Result<u32, spi::Error> {
+    let mut crc = Crc32::new();
+    let mut buf = [0; PAGE_SIZE as usize];
+    for offset in (0..size).step_by(buf.len()) {
+        let chunk = &mut buf[..(size - offset).min(PAGE_SIZE) as usize];
+        spi_flash.read(CENTRAL_STAGING_OFFSET + offset, chunk)?;
+        crc.update(chunk);
+    }
+    Ok(crc.finish())
+}
+
+/// Start the firmware at `address` as if it came out of reset.
+unsafe fn start_firmware(address: u32) -> ! {
+    // Don't leave the loader's interrupts enabled or pending, the firmware enables its own.
+    // PRIMASK stays clear, the firmware expects interrupts to be unmasked.
+    let nvic = unsafe { &*NVIC::PTR };
+    for (icer, icpr) in nvic.icer.iter().zip(nvic.icpr.iter()) {
+        unsafe {
+            icer.write(!0);
+            icpr.write(!0);
+        }
+    }
+    unsafe {
+        (*SCB::PTR).vtor.write(address);
+        cortex_m::asm::bootload(address as *const u32)
+    }
+}
@@ -1,3 +1,4 @@
+#[cfg(feature = "_stm32")]
 macro_rules! config_matrix_pins_stm32 {
     (peripherals: $p:ident, input: [$($in_pin:ident), *], output: [$($out_pin:ident), +]) => {
         {
@@ -10,3 +11,17 @@ macro_rules! config_matrix_pins_stm32 {
         }
     };
 }
+
+#[cfg(feature = "rp2040")]
+macro_rules! config_matrix_pins_rp {
+    (peripherals: $p:ident, input: [$($in_pin:ident), *], output: [$($out_pin:ident), +]) => {
+        {
+            let mut output_pins = [$(Output::new($p.$out_pin, embassy_rp::gpio::Level::Low)), +];
+            let input_pins = [$(Input::new($p.$in_pin, embassy_rp::gpio::Pull::Down)), +];
+            output_pins.iter_mut().for_each(|p| {
+                p.set_low();
+            });
+            (input_pins, output_pins)
+        }
+    };
+}
@@ -0,0 +1,29 @@
+//! Flash partitions for firmware updates and the firmware's data.
+//!
+//! The internal flash is laid out by build.rs for the selected chip: the loader, its state and the
+//! active firmware. The peripheral receives updates into the upper half of its own flash, the
+//! central stages them in its W25Q, next to rmk's storage, the settings and the dynamic macros.
+
+// Every binary uses a different part of the layout
+#![allow(dead_code)]
+
+include!(concat!(env!("OUT_DIR"), "/partitions_generated.rs"));
+
+/// rmk's storage at the start of the W25Q
+pub(crate) const STORAGE_OFFSET: u32 = 0;
+pub(crate) const STORAGE_SIZE: u32 = 64 * 1024;
+/// Image the host sends for the peripheral, before it is streamed over the link
+pub(crate) const PERIPHERAL_STAGING_OFFSET: u32 = STORAGE_OFFSET + STORAGE_SIZE;
+pub(crate) const PERIPHERAL_STAGING_SIZE: u32 = 512 * 1024;
+/// Image the host sends for the central, installed by the loader
+pub(crate) const CENTRAL_STAGING_OFFSET: u32 = PERIPHERAL_STAGING_OFFSET + PERIPHERAL_STAGING_SIZE;
+pub(crate) const CENTRAL_STAGING_SIZE: u32 = 512 * 1024;
+/// The central's previous firmware, to roll back to if the update doesn't come up
+pub(crate) const CENTRAL_BACKUP_OFFSET: u32 = CENTRAL_STAGING_OFFSET + CENTRAL_STAGING_SIZE;
+pub(crate) const CENTRAL_BACKUP_SIZE: u32 = 512 * 1024;
+/// Settings the host changed, see `settings`
+pub(crate) const SETTINGS_OFFSET: u32 = CENTRAL_BACKUP_OFFSET + CENTRAL_BACKUP_SIZE;
+pub(crate) const SETTINGS_SIZE: u32 = 4 * 1024;
+/// Recorded dynamic macros, a sector per slot
+pub(crate) const MACROS_OFFSET: u32 = SETTINGS_OFFSET + SETTINGS_SIZE;
+pub(crate) const MACROS_SIZE: u32 = 64 * 1024;
@@ -3,23 +3,38 @@
 
 #[macro_use]
 mod macros;
+mod board;
+#[cfg(feature = "ota")]
+mod boot_state;
+mod bootloader;
+mod build_info;
+#[cfg(feature = "ota")]
+mod crc;
 mod keymap;
+#[cfg(feature = "ota")]
+mod partitions;
+mod split_link;
+#[cfg(feature = "ota")]
+mod update_receiver;
 mod vial;
 
 use crate::keymap::{RIGHT_COL, RIGHT_ROW};
 use defmt::info;
+#[cfg(not(feature = "ota"))]
+use defmt::warn;
 use embassy_executor::Spawner;
 use embassy_stm32::bind_interrupts;
+#[cfg(feature = "ota")]
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Input, Output};
 use embassy_stm32::peripherals::{self};
-use embassy_stm32::time::Hertz;
 use embassy_stm32::usart::{self, BufferedInterruptHandler, BufferedUart};
-use rmk::channel::EVENT_CHANNEL;
 use rmk::debounce::default_debouncer::DefaultDebouncer;
-use rmk::futures::future::join;
+use rmk::event::Event;
+use rmk::futures::future::join4;
+use rmk::input_device::InputDevice;
 use rmk::matrix::Matrix;
-use rmk::run_devices;
-use rmk::split::peripheral::run_rmk_split_peripheral;
+use split_link::{KEYS_OUTBOX, LINK_INBOX, LINK_OUTBOX, LinkMessage, SplitLink};
 use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
@@ -30,34 +45,18 @@ bind_interrupts!(struct Irqs {
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
+    // Before anything touches the clocks
+    bootloader::jump_if_requested();
+
     // RCC config
-    let config = {
-        use embassy_stm32::rcc::*;
-        let mut config = embassy_stm32::Config::default();
-        config.rcc.hse = Some(Hse {
-            freq: Hertz(25_000_000),
-            mode: HseMode::Oscillator,
-        });
-        config.rcc.pll_src = PllSource::HSE;
-        config.rcc.pll = Some(Pll {
-            prediv: PllPreDiv::DIV25,
-            mul: PllMul::MUL336,
-            divp: Some(PllPDiv::DIV4), // 8mhz / 4 * 168 / 4 = 84Mhz. (=MAX SYSCLK FREQENCY FOR f401)
-            divq: Some(PllQDiv::DIV7), // 8mhz / 4 * 168 / 7 = 48Mhz. (=Needed for clk48)
-            divr: None,
-        });
-        config.rcc.ahb_pre = AHBPrescaler::DIV1;
-        config.rcc.apb1_pre = APBPrescaler::DIV4;
-        config.rcc.apb2_pre = APBPrescaler::DIV2;
-        config.rcc.sys = Sysclk::PLL1_P;
-        config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
-        config
-    };
+    let (config, clock_source) = board::clock_config();
 
     // Initialize peripherals
     info!("Embassy Init Pre");
     let p = embassy_stm32::init(config);
-    info!("Embassy Init");
+    info!("Embassy Init, clock source: {}", clock_source);
+    build_info::set_clock_source(clock_source);
+    info!("Firmware {}", build_info::BUILD_INFO);
 
     // Pin config
     // COL 2 ROW
@@ -86,14 +85,59 @@ async fn main(_spawner: Spawner) {
         usart::Config::default(),
     )
     .unwrap();
+    let (uart_tx, uart_rx) = uart.split();
+    let link = SplitLink::new(uart_tx, uart_rx);
+
+    // Receive firmware updates from the central
+    #[cfg(feature = "ota")]
+    let mut update_receiver = update_receiver::UpdateReceiver::new(Flash::new_blocking(p.FLASH));
+    #[cfg(feature = "ota")]
+    let update_receiver = update_receiver.run();
+    #[cfg(not(feature = "ota"))]
+    let update_receiver = core::future::pending::<()>();
 
     info!("Starting!");
     // Start
-    join(
-        run_devices! (
-            (matrix) => EVENT_CHANNEL,
-        ),
-        run_rmk_split_peripheral(uart),
+    join4(
+        send_keys(&mut matrix),
+        link.run(),
+        run_link_messages(),
+        update_receiver,
     )
     .await;
 }
+
+/// Send the key events of the matrix to the central, which hands them to rmk
+async fn send_keys(matrix: &mut impl InputDevice) {
+    loop {
+        if let Event::Key(event) = matrix.read_event().await {
+            KEYS_OUTBOX.send(event).await;
+        }
+    }
+}
+
+/// Handle control messages of the central
+async fn run_link_messages() {
+    // Tell the central which firmware we run, in case it is up already
+    LINK_OUTBOX.send(build_info_message()).await;
+    loop {
+        match LINK_INBOX.receive().await {
+            LinkMessage::BuildInfoRequest => LINK_OUTBOX.send(build_info_message()).await,
+            LinkMessage::EnterDfu => {
+                info!("Rebooting into DFU");
+                bootloader::reboot_into_dfu()
+            }
+            #[cfg(feature = "ota")]
+            message => update_receiver::UPDATE_MESSAGES.send(message).await,
+            #[cfg(not(feature = "ota"))]
+            message => warn!("Unexpected link message {}", message),
+        }
+    }
+}
+
+fn build_info_message() -> LinkMessage {
+    LinkMessage::BuildInfo {
+        build_info: build_info::BUILD_INFO.try_into().unwrap(),
+        clock_source: build_info::clock_source(),
+    }
+}
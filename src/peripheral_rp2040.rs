@@ -0,0 +1,101 @@
+#![no_main]
+#![no_std]
+
+#[macro_use]
+mod macros;
+mod build_info;
+mod keymap;
+mod split_link;
+mod vial;
+
+use crate::keymap::{RIGHT_COL, RIGHT_ROW};
+use defmt::{info, warn};
+use embassy_executor::Spawner;
+use embassy_rp::bind_interrupts;
+use embassy_rp::gpio::{Input, Output};
+use embassy_rp::peripherals::UART0;
+use embassy_rp::uart::{self, BufferedInterruptHandler, BufferedUart};
+use rmk::debounce::default_debouncer::DefaultDebouncer;
+use rmk::event::Event;
+use rmk::futures::future::join3;
+use rmk::input_device::InputDevice;
+use rmk::matrix::Matrix;
+use split_link::{KEYS_OUTBOX, LINK_INBOX, LINK_OUTBOX, LinkMessage, SplitLink};
+use static_cell::StaticCell;
+
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    UART0_IRQ => BufferedInterruptHandler<UART0>;
+});
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    // Initialize peripherals
+    info!("Embassy Init Pre");
+    let p = embassy_rp::init(Default::default());
+    info!("Embassy Init");
+
+    // Pin config
+    // COL 2 ROW
+    let (input_pins, output_pins) = config_matrix_pins_rp!(peripherals: p,
+        input: [PIN_29, PIN_28, PIN_27, PIN_26, PIN_22],
+        output: [PIN_9, PIN_8, PIN_7, PIN_6, PIN_5, PIN_4, PIN_3, PIN_2]
+    );
+
+    // Initialize the matrix + keyboard
+    let debouncer = DefaultDebouncer::<RIGHT_ROW, RIGHT_COL>::new();
+    let mut matrix =
+        Matrix::<_, _, _, RIGHT_ROW, RIGHT_COL>::new(input_pins, output_pins, debouncer);
+
+    // TX on GP0, RX on GP1, crossed over to the central
+    static UART_OUT_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+    static UART_IN_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+    let uart = BufferedUart::new(
+        p.UART0,
+        Irqs,
+        p.PIN_0,
+        p.PIN_1,
+        &mut UART_OUT_BUFFER.init([0; 64])[..],
+        &mut UART_IN_BUFFER.init([0; 64])[..],
+        uart::Config::default(),
+    );
+    let (uart_tx, uart_rx) = uart.split();
+    let link = SplitLink::new(uart_tx, uart_rx);
+
+    info!("Starting!");
+    // Start
+    join3(send_keys(&mut matrix), link.run(), run_link_messages()).await;
+}
+
+/// Send the key events of the matrix to the central, which hands them to rmk
+async fn send_keys(matrix: &mut impl InputDevice) {
+    loop {
+        if let Event::Key(event) = matrix.read_event().await {
+            KEYS_OUTBOX.send(event).await;
+        }
+    }
+}
+
+/// Handle control messages of the central. Updates over the link are STM32 only.
+async fn run_link_messages() {
+    // Tell the central which firmware we run, in case it is up already
+    LINK_OUTBOX.send(build_info_message()).await;
+    loop {
+        match LINK_INBOX.receive().await {
+            LinkMessage::BuildInfoRequest => LINK_OUTBOX.send(build_info_message()).await,
+            LinkMessage::EnterDfu => {
+                info!("Rebooting into the USB bootloader");
+                embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+            }
+            message => warn!("Unexpected link message {}", message),
+        }
+    }
+}
+
+fn build_info_message() -> LinkMessage {
+    LinkMessage::BuildInfo {
+        build_info: build_info::BUILD_INFO.try_into().unwrap(),
+        clock_source: build_info::clock_source(),
+    }
+}
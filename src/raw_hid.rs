@@ -0,0 +1,251 @@
+//! Firmware commands on the Vial raw HID interface.
+//!
+//! rmk owns the USB stack, so its driver is wrapped to look at the raw HID reports. Reports
+//! starting with `NIO_COMMAND` are answered here. rmk answers every report it reads, so it gets a
+//! harmless VIA request in their place and its answer is swapped for ours.
+//!
+//! Commands that write the flash are handed to the tasks doing it and answered right away, so the
+//! endpoint isn't held up by an erase.
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_usb_driver::{
+    Driver, Endpoint, EndpointAllocError, EndpointError, EndpointIn, EndpointInfo, EndpointOut,
+    EndpointType,
+};
+
+use crate::build_info::{self, BUILD_INFO};
+use crate::dfu::DFU_REQUEST;
+use crate::link_messages;
+use crate::settings::{self, TAP_HOLD_LEN, TapHoldSettings};
+#[cfg(feature = "ota")]
+use crate::updater::{self, UpdateError, UpdateRequest, UpdateTarget};
+
+const REPORT_SIZE: usize = 32;
+
+/// Prefix of the firmware's commands, unused by VIA (0x01..=0x15, 0xFF) and Vial (0xFE).
+/// Reports are `[NIO_COMMAND, command, args..]`, answers `[NIO_COMMAND, command, status, data..]`.
+const NIO_COMMAND: u8 = 0xF0;
+const VIA_GET_PROTOCOL_VERSION: u8 = 0x01;
+const VIA_BOOTLOADER_JUMP: u8 = 0x0B;
+
+/// Reboot both halves into the DFU bootloader
+const CMD_ENTER_DFU: u8 = 0x01;
+/// Start an update: `[target, size, crc]`, numbers are little endian u32.
+/// Update commands answer with an `UpdateError` as status when the updater can't take them, `Busy`
+/// until it took the previous one. Whether they succeed shows in `CMD_UPDATE_STATUS`.
+#[cfg(feature = "ota")]
+const CMD_UPDATE_BEGIN: u8 = 0x02;
+/// Part of the image, in order: `[offset, len, data..]` with a single byte `len`
+#[cfg(feature = "ota")]
+const CMD_UPDATE_DATA: u8 = 0x03;
+/// Verify the image and install it
+#[cfg(feature = "ota")]
+const CMD_UPDATE_FINISH: u8 = 0x04;
+/// Answers `[state, progress, error]`, see `UpdateState` and `UpdateError`
+#[cfg(feature = "ota")]
+const CMD_UPDATE_STATUS: u8 = 0x05;
+/// Build info of a half: `[half, offset]`, answers `[len, clock_source, BUILD_INFO[offset..]]`
+/// with the `ClockSource` the half runs from, so a board that fell back to the HSI can be told.
+/// The peripheral's is `STATUS_UNAVAILABLE` until it sent it over the link.
+const CMD_BUILD_INFO: u8 = 0x06;
+/// Tap-hold settings in use, answers `TapHoldSettings::encode`
+const CMD_GET_TAP_HOLD: u8 = 0x07;
+/// Save tap-hold settings, args as `CMD_GET_TAP_HOLD` answers. The central reboots to apply them
+/// once they are saved, `STATUS_FAILED` while other settings are being saved.
+const CMD_SET_TAP_HOLD: u8 = 0x08;
+
+/// The halves, as arguments of `CMD_UPDATE_BEGIN` and `CMD_BUILD_INFO`
+const HALF_CENTRAL: u8 = 0x00;
+const HALF_PERIPHERAL: u8 = 0x01;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_FAILED: u8 = 0x01;
+const STATUS_UNAVAILABLE: u8 = 0xFE;
+const STATUS_UNKNOWN_COMMAND: u8 = 0xFF;
+
+/// Our answer to the report rmk is currently processing
+static PENDING_RESPONSE: Mutex<CriticalSectionRawMutex, Cell<Option<[u8; REPORT_SIZE]>>> =
+    Mutex::new(Cell::new(None));
+
+fn handle_report(report: &[u8]) -> Option<[u8; REPORT_SIZE]> {
+    let mut response = [0; REPORT_SIZE];
+    response[..2].copy_from_slice(&report[..2]);
+    response[2] = match report {
+        // Also serves VIA's own bootloader jump, which rmk can't do on the STM32
+        [VIA_BOOTLOADER_JUMP, ..] | [NIO_COMMAND, CMD_ENTER_DFU, ..] => {
+            DFU_REQUEST.signal(());
+            STATUS_OK
+        }
+        #[cfg(feature = "ota")]
+        [
+            NIO_COMMAND,
+            command @ CMD_UPDATE_BEGIN..=CMD_UPDATE_STATUS,
+            args @ ..,
+        ] => match update_command(*command, args, &mut response[3..]) {
+            Ok(()) => STATUS_OK,
+            Err(e) => e as u8,
+        },
+        [NIO_COMMAND, CMD_BUILD_INFO, half, offset, ..] => {
+            build_info(*half, *offset as usize, &mut response[3..])
+        }
+        [NIO_COMMAND, CMD_GET_TAP_HOLD, ..] => match settings::tap_hold() {
+            Some(tap_hold) => {
+                response[3..3 + TAP_HOLD_LEN].copy_from_slice(&tap_hold.encode());
+                STATUS_OK
+            }
+            None => STATUS_UNAVAILABLE,
+        },
+        [NIO_COMMAND, CMD_SET_TAP_HOLD, args @ ..] => {
+            if settings::queue_tap_hold(TapHoldSettings::decode(args)) {
+                STATUS_OK
+            } else {
+                STATUS_FAILED
+            }
+        }
+        [NIO_COMMAND, ..] => STATUS_UNKNOWN_COMMAND,
+        _ => return None,
+    };
+    Some(response)
+}
+
+fn build_info(half: u8, offset: usize, data: &mut [u8]) -> u8 {
+    let peripheral;
+    let (build_info, clock_source) = match half {
+        HALF_CENTRAL => (BUILD_INFO, build_info::clock_source()),
+        HALF_PERIPHERAL => match link_messages::peripheral_build_info() {
+            Some((build_info, clock_source)) => {
+                peripheral = build_info;
+                (peripheral.as_str(), clock_source)
+            }
+            None => return STATUS_UNAVAILABLE,
+        },
+        _ => return STATUS_UNKNOWN_COMMAND,
+    };
+    let chunk = build_info.as_bytes().get(offset..).unwrap_or_default();
+    let len = chunk.len().min(data.len() - 2);
+    data[0] = build_info.len() as u8;
+    data[1] = clock_source as u8;
+    data[2..2 + len].copy_from_slice(&chunk[..len]);
+    STATUS_OK
+}
+
+#[cfg(feature = "ota")]
+fn update_command(command: u8, args: &[u8], data: &mut [u8]) -> Result<(), UpdateError> {
+    let word = |i: usize| u32::from_le_bytes([args[i], args[i + 1], args[i + 2], args[i + 3]]);
+    let request = match command {
+        CMD_UPDATE_BEGIN => UpdateRequest::Begin {
+            target: match args[0] {
+                HALF_CENTRAL => UpdateTarget::Central,
+                HALF_PERIPHERAL => UpdateTarget::Peripheral,
+                _ => return Err(UpdateError::BadTarget),
+            },
+            size: word(1),
+            crc: word(5),
+        },
+        CMD_UPDATE_DATA => {
+            let len = (args[4] as usize).min(updater::MAX_DATA_LEN);
+            UpdateRequest::Data {
+                offset: word(0),
+                data: heapless::Vec::from_slice(&args[5..5 + len]).unwrap(),
+            }
+        }
+        CMD_UPDATE_FINISH => UpdateRequest::Finish,
+        _ => {
+            let (state, progress, error) = updater::status();
+            data[0] = state;
+            data[1..5].copy_from_slice(&progress.to_le_bytes());
+            data[5] = error;
+            return Ok(());
+        }
+    };
+    updater::request(request)
+}
+
+/// USB driver passing everything through to the wrapped driver, except firmware commands.
+pub(crate) struct RawHidDriver<D> {
+    inner: D,
+}
+
+impl<D> RawHidDriver<D> {
+    pub(crate) fn new(inner: D) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'d, D: Driver<'d>> Driver<'d> for RawHidDriver<D> {
+    type EndpointOut = RawHidEndpoint<D::EndpointOut>;
+    type EndpointIn = RawHidEndpoint<D::EndpointIn>;
+    type ControlPipe = D::ControlPipe;
+    type Bus = D::Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        self.inner
+            .alloc_endpoint_out(ep_type, max_packet_size, interval_ms)
+            .map(|inner| RawHidEndpoint { inner })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        self.inner
+            .alloc_endpoint_in(ep_type, max_packet_size, interval_ms)
+            .map(|inner| RawHidEndpoint { inner })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        self.inner.start(control_max_packet_size)
+    }
+}
+
+/// Endpoint of the wrapped driver. Only raw HID reports are `REPORT_SIZE` long.
+pub(crate) struct RawHidEndpoint<E> {
+    inner: E,
+}
+
+impl<E: Endpoint> Endpoint for RawHidEndpoint<E> {
+    fn info(&self) -> &EndpointInfo {
+        self.inner.info()
+    }
+
+    async fn wait_enabled(&mut self) {
+        self.inner.wait_enabled().await;
+        // Endpoints are enabled once the host configured the device
+        #[cfg(feature = "ota")]
+        updater::healthy();
+    }
+}
+
+impl<E: EndpointOut> EndpointOut for RawHidEndpoint<E> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let len = self.inner.read(buf).await?;
+        if len == REPORT_SIZE {
+            if let Some(response) = handle_report(&buf[..len]) {
+                PENDING_RESPONSE.lock(|pending| pending.set(Some(response)));
+                buf[..len].fill(0);
+                buf[0] = VIA_GET_PROTOCOL_VERSION;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<E: EndpointIn> EndpointIn for RawHidEndpoint<E> {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() == REPORT_SIZE {
+            if let Some(response) = PENDING_RESPONSE.lock(|pending| pending.take()) {
+                return self.inner.write(&response).await;
+            }
+        }
+        self.inner.write(buf).await
+    }
+}
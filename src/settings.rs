@@ -0,0 +1,180 @@
+//! Settings the host can change, persisted in the external flash.
+//!
+//! rmk takes its behaviour config when it starts, so changed settings apply after a reboot. The
+//! keymap's settings are used until the host saved its own.
+
+use core::cell::Cell;
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::NorFlash;
+use rmk::config::TapHoldConfig;
+
+use crate::keymap;
+
+const MAGIC: u32 = 0x5345_5454;
+
+/// `TapHoldSettings` as sent over raw HID and stored after the magic
+pub(crate) const TAP_HOLD_LEN: usize = 5;
+
+const PERMISSIVE_HOLD: u8 = 1 << 0;
+const HOLD_ON_OTHER_PRESS: u8 = 1 << 1;
+const CHORDAL_HOLD: u8 = 1 << 2;
+
+/// The tunable part of rmk's `TapHoldConfig`
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct TapHoldSettings {
+    /// A tap-hold key held this long is a hold (tapping term)
+    pub(crate) hold_timeout_ms: u16,
+    /// A tap-hold key pressed within this time after another key is a tap, so modifiers don't
+    /// trigger while typing (flow tap)
+    pub(crate) prior_idle_time_ms: u16,
+    /// A key tapped while a tap-hold key is held makes it a hold
+    pub(crate) permissive_hold: bool,
+    /// Any key pressed while a tap-hold key is held makes it a hold
+    pub(crate) hold_on_other_press: bool,
+    /// A key of the same hand pressed while a tap-hold key is held makes it a tap
+    pub(crate) chordal_hold: bool,
+}
+
+impl TapHoldSettings {
+    fn from_config(config: &TapHoldConfig) -> Self {
+        Self {
+            hold_timeout_ms: config.hold_timeout.as_millis() as u16,
+            prior_idle_time_ms: config.prior_idle_time.as_millis() as u16,
+            permissive_hold: config.permissive_hold,
+            hold_on_other_press: config.hold_on_other_press,
+            chordal_hold: config.chordal_hold,
+        }
+    }
+
+    fn apply(&self, config: &mut TapHoldConfig) {
+        config.hold_timeout = Duration::from_millis(self.hold_timeout_ms as u64);
+        config.prior_idle_time = Duration::from_millis(self.prior_idle_time_ms as u64);
+        config.permissive_hold = self.permissive_hold;
+        config.hold_on_other_press = self.hold_on_other_press;
+        config.chordal_hold = self.chordal_hold;
+    }
+
+    /// `[hold_timeout_ms, prior_idle_time_ms, flags]`, numbers are little endian u16
+    pub(crate) fn encode(&self) -> [u8; TAP_HOLD_LEN] {
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.permissive_hold, PERMISSIVE_HOLD),
+            (self.hold_on_other_press, HOLD_ON_OTHER_PRESS),
+            (self.chordal_hold, CHORDAL_HOLD),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        let [timeout_lo, timeout_hi] = self.hold_timeout_ms.to_le_bytes();
+        let [idle_lo, idle_hi] = self.prior_idle_time_ms.to_le_bytes();
+        [timeout_lo, timeout_hi, idle_lo, idle_hi, flags]
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Self {
+        Self {
+            hold_timeout_ms: u16::from_le_bytes([data[0], data[1]]),
+            prior_idle_time_ms: u16::from_le_bytes([data[2], data[3]]),
+            permissive_hold: data[4] & PERMISSIVE_HOLD != 0,
+            hold_on_other_press: data[4] & HOLD_ON_OTHER_PRESS != 0,
+            chordal_hold: data[4] & CHORDAL_HOLD != 0,
+        }
+    }
+}
+
+/// The settings rmk runs with
+static TAP_HOLD: Mutex<CriticalSectionRawMutex, Cell<Option<TapHoldSettings>>> =
+    Mutex::new(Cell::new(None));
+
+enum Request {
+    SaveTapHold(TapHoldSettings),
+    /// Forget the saved settings and rmk's storage
+    Reset,
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 1> = Channel::new();
+static RESULT: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// Tap-hold config of the keymap, with the settings saved by the host applied.
+pub(crate) async fn tap_hold_config<F: NorFlash>(flash: &mut F) -> TapHoldConfig {
+    let mut config = keymap::get_tap_hold_config();
+    let mut buf = [0; 4 + TAP_HOLD_LEN];
+    match flash.read(0, &mut buf).await {
+        Ok(()) if buf[..4] == MAGIC.to_le_bytes() => {
+            let settings = TapHoldSettings::decode(&buf[4..]);
+            info!("Tap-hold settings {}", settings);
+            settings.apply(&mut config);
+        }
+        Ok(()) => {}
+        Err(_) => warn!("Failed to read the settings"),
+    }
+    TAP_HOLD.lock(|cell| cell.set(Some(TapHoldSettings::from_config(&config))));
+    config
+}
+
+/// The tap-hold settings in use
+pub(crate) fn tap_hold() -> Option<TapHoldSettings> {
+    TAP_HOLD.lock(|cell| cell.get())
+}
+
+/// Save tap-hold settings, they apply after the reboot that follows.
+pub(crate) async fn save_tap_hold(settings: TapHoldSettings) -> bool {
+    request(Request::SaveTapHold(settings)).await
+}
+
+/// Hand tap-hold settings of the host to `run_settings` without waiting for the flash, the central
+/// reboots once they are saved. False while other settings are being saved.
+pub(crate) fn queue_tap_hold(settings: TapHoldSettings) -> bool {
+    REQUESTS.try_send(Request::SaveTapHold(settings)).is_ok()
+}
+
+/// Forget the saved settings and rmk's storage, then reboot.
+pub(crate) async fn reset() -> bool {
+    request(Request::Reset).await
+}
+
+async fn request(request: Request) -> bool {
+    // Left from a queued request nobody waited for
+    RESULT.reset();
+    REQUESTS.send(request).await;
+    RESULT.wait().await
+}
+
+/// Save the settings to `flash`. `storage` is rmk's, to reset it.
+pub(crate) async fn run_settings<F: NorFlash>(mut flash: F, mut storage: F) {
+    loop {
+        let saved = match REQUESTS.receive().await {
+            Request::SaveTapHold(settings) => {
+                info!("Saving tap-hold settings {}", settings);
+                // Padded to the flash's write size
+                let mut record = [0xFF; 16];
+                record[..4].copy_from_slice(&MAGIC.to_le_bytes());
+                record[4..4 + TAP_HOLD_LEN].copy_from_slice(&settings.encode());
+                match flash.erase(0, F::ERASE_SIZE as u32).await {
+                    Ok(()) => flash.write(0, &record).await.is_ok(),
+                    Err(_) => false,
+                }
+            }
+            Request::Reset => {
+                info!("Resetting the settings and storage");
+                let settings = flash.erase(0, flash.capacity() as u32).await;
+                let storage = storage.erase(0, storage.capacity() as u32).await;
+                settings.is_ok() && storage.is_ok()
+            }
+        };
+        RESULT.signal(saved);
+        if !saved {
+            warn!("Failed to write the settings");
+            continue;
+        }
+        info!("Rebooting to apply the settings");
+        // Give the answer to the host time to go out
+        Timer::after_millis(100).await;
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+}
@@ -0,0 +1,78 @@
+//! Blocking access to the W25Q of the central, just enough for the loader to copy images.
+
+use embassy_stm32::gpio::Output;
+use embassy_stm32::mode::Blocking;
+use embassy_stm32::spi::{Error, Spi};
+
+const READ_DATA: u8 = 0x03;
+const WRITE_ENABLE: u8 = 0x06;
+const PAGE_PROGRAM: u8 = 0x02;
+const SECTOR_ERASE: u8 = 0x20;
+const READ_STATUS: u8 = 0x05;
+const STATUS_BUSY: u8 = 0x01;
+
+pub(crate) const SECTOR_SIZE: u32 = 4096;
+pub(crate) const PAGE_SIZE: u32 = 256;
+
+pub(crate) struct SpiFlash<'d> {
+    spi: Spi<'d, Blocking>,
+    cs: Output<'d>,
+}
+
+impl<'d> SpiFlash<'d> {
+    pub(crate) fn new(spi: Spi<'d, Blocking>, cs: Output<'d>) -> Self {
+        Self { spi, cs }
+    }
+
+    pub(crate) fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+        self.transaction(|spi| {
+            spi.blocking_write(&command(READ_DATA, address))?;
+            spi.blocking_read(buf)
+        })
+    }
+
+    pub(crate) fn erase_sector(&mut self, address: u32) -> Result<(), Error> {
+        self.transaction(|spi| spi.blocking_write(&[WRITE_ENABLE]))?;
+        self.transaction(|spi| spi.blocking_write(&command(SECTOR_ERASE, address)))?;
+        self.wait_ready()
+    }
+
+    /// Program `data` at `address`, which must not cross a page.
+    pub(crate) fn program_page(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.transaction(|spi| spi.blocking_write(&[WRITE_ENABLE]))?;
+        self.transaction(|spi| {
+            spi.blocking_write(&command(PAGE_PROGRAM, address))?;
+            spi.blocking_write(data)
+        })?;
+        self.wait_ready()
+    }
+
+    fn wait_ready(&mut self) -> Result<(), Error> {
+        loop {
+            let mut status = [0];
+            self.transaction(|spi| {
+                spi.blocking_write(&[READ_STATUS])?;
+                spi.blocking_read(&mut status)
+            })?;
+            if status[0] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn transaction<R>(
+        &mut self,
+        f: impl FnOnce(&mut Spi<'d, Blocking>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        self.cs.set_low();
+        let result = f(&mut self.spi);
+        self.cs.set_high();
+        result
+    }
+}
+
+/// Instruction with a 24 bit address
+fn command(instruction: u8, address: u32) -> [u8; 4] {
+    let [_, a2, a1, a0] = address.to_be_bytes();
+    [instruction, a2, a1, a0]
+}
@@ -0,0 +1,184 @@
+//! Link between the halves.
+//!
+//! The key events of the peripheral and the firmware's own control messages share the UART. Both
+//! are sent in frames of `[SYNC, channel, len, payload.., checksum]`, so both halves have to run
+//! this firmware.
+//!
+//! rmk's split protocol isn't used: the peripheral's key events go to the central's `KeyFilter`,
+//! which hands them to rmk with the central's own, see `key_filter`.
+
+use defmt::{Format, warn};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+use rmk::event::{KeyPos, KeyboardEvent, KeyboardEventPos};
+use rmk::futures::future::join3;
+use serde::{Deserialize, Serialize};
+
+use crate::build_info::{BUILD_INFO_LEN, ClockSource};
+
+const SYNC: u8 = 0xA5;
+const CHANNEL_KEYS: u8 = 0;
+const CHANNEL_CONTROL: u8 = 1;
+/// Frame length is a single byte
+const MAX_PAYLOAD: usize = 255;
+/// Image bytes in an `UpdateChunk`, leaves room for the rest of the message in a frame
+pub(crate) const UPDATE_CHUNK_SIZE: usize = 128;
+
+/// Control messages between the halves, next to the key events.
+#[derive(Clone, Serialize, Deserialize, Format)]
+pub(crate) enum LinkMessage {
+    /// Reboot into the DFU bootloader
+    EnterDfu,
+    /// Start of a firmware update of the peripheral
+    UpdateBegin { size: u32, crc: u32 },
+    /// Part of the image at `offset`
+    UpdateChunk {
+        offset: u32,
+        data: Vec<u8, UPDATE_CHUNK_SIZE>,
+    },
+    /// The whole image has been sent, verify and install it
+    UpdateFinish,
+    /// The peripheral expects the image from `offset` next
+    UpdateAck { offset: u32 },
+    /// The peripheral verified the image and reboots to install it
+    UpdateDone,
+    /// The peripheral gave up on the update
+    UpdateFailed,
+    /// Ask the peripheral for its `BuildInfo`
+    BuildInfoRequest,
+    /// The peripheral's build info and clock source, also sent when it boots
+    BuildInfo {
+        build_info: String<BUILD_INFO_LEN>,
+        clock_source: ClockSource,
+    },
+}
+
+/// Control messages to send to the other half
+pub(crate) static LINK_OUTBOX: Channel<CriticalSectionRawMutex, LinkMessage, 4> = Channel::new();
+/// Control messages received from the other half
+pub(crate) static LINK_INBOX: Channel<CriticalSectionRawMutex, LinkMessage, 4> = Channel::new();
+/// Key events of the peripheral's matrix to send to the central
+pub(crate) static KEYS_OUTBOX: Channel<CriticalSectionRawMutex, KeyboardEvent, 16> = Channel::new();
+/// Key events received from the peripheral, by its own row and column
+pub(crate) static KEYS_INBOX: Channel<CriticalSectionRawMutex, KeyboardEvent, 16> = Channel::new();
+
+pub(crate) struct SplitLink<Tx, Rx> {
+    tx: Mutex<NoopRawMutex, Tx>,
+    rx: Mutex<NoopRawMutex, Rx>,
+}
+
+impl<Tx: Write, Rx: Read> SplitLink<Tx, Rx> {
+    pub(crate) fn new(tx: Tx, rx: Rx) -> Self {
+        Self {
+            tx: Mutex::new(tx),
+            rx: Mutex::new(rx),
+        }
+    }
+
+    /// Receive frames and send queued key events and control messages.
+    pub(crate) async fn run(&self) {
+        join3(
+            self.receive_frames(),
+            self.send_key_events(),
+            self.send_control_messages(),
+        )
+        .await;
+    }
+
+    async fn send_frame(&self, channel: u8, payload: &[u8]) -> Result<(), Tx::Error> {
+        let mut tx = self.tx.lock().await;
+        tx.write_all(&[SYNC, channel, payload.len() as u8]).await?;
+        tx.write_all(payload).await?;
+        tx.write_all(&[checksum(channel, payload)]).await
+    }
+
+    /// Key events are sent as `[row, col, pressed]`
+    async fn send_key_events(&self) {
+        loop {
+            let event = KEYS_OUTBOX.receive().await;
+            let KeyboardEventPos::Key(KeyPos { row, col }) = event.pos else {
+                continue;
+            };
+            if self
+                .send_frame(CHANNEL_KEYS, &[row, col, event.pressed as u8])
+                .await
+                .is_err()
+            {
+                warn!("Failed to send a key event");
+            }
+        }
+    }
+
+    async fn send_control_messages(&self) {
+        let mut buf = [0; MAX_PAYLOAD];
+        loop {
+            let message = LINK_OUTBOX.receive().await;
+            let Ok(payload) = postcard::to_slice(&message, &mut buf) else {
+                warn!("Link message too large: {}", message);
+                continue;
+            };
+            if self.send_frame(CHANNEL_CONTROL, payload).await.is_err() {
+                warn!("Failed to send link message {}", message);
+            }
+        }
+    }
+
+    async fn receive_frames(&self) {
+        let mut rx = self.rx.lock().await;
+        let mut buf = [0; MAX_PAYLOAD];
+        loop {
+            let Some((channel, len)) = read_frame(&mut *rx, &mut buf).await else {
+                warn!("Dropped malformed frame from the other half");
+                continue;
+            };
+            let payload = &buf[..len];
+            match channel {
+                CHANNEL_KEYS => match *payload {
+                    [row, col, pressed] => {
+                        KEYS_INBOX
+                            .send(KeyboardEvent::key(row, col, pressed != 0))
+                            .await
+                    }
+                    _ => warn!("Malformed key event"),
+                },
+                CHANNEL_CONTROL => match postcard::from_bytes(payload) {
+                    // Waiting for room would hold up the key events behind it
+                    Ok(message) => {
+                        if LINK_INBOX.try_send(message).is_err() {
+                            warn!("Link inbox full, dropped a control message");
+                        }
+                    }
+                    Err(_) => warn!("Unknown link message"),
+                },
+                _ => warn!("Frame on unknown channel {}", channel),
+            }
+        }
+    }
+}
+
+/// Read the next frame into `buf`, returning its channel and length.
+async fn read_frame<R: Read>(rx: &mut R, buf: &mut [u8; MAX_PAYLOAD]) -> Option<(u8, usize)> {
+    let mut byte = [0];
+    // Skip to the start of a frame
+    while byte[0] != SYNC {
+        rx.read_exact(&mut byte).await.ok()?;
+    }
+    let mut header = [0; 2];
+    rx.read_exact(&mut header).await.ok()?;
+    let [channel, len] = header;
+    let payload = &mut buf[..len as usize];
+    rx.read_exact(payload).await.ok()?;
+    rx.read_exact(&mut byte).await.ok()?;
+    (byte[0] == checksum(channel, payload)).then_some((channel, len as usize))
+}
+
+fn checksum(channel: u8, payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(channel ^ payload.len() as u8, |sum, b| {
+            sum.rotate_left(1) ^ b
+        })
+}
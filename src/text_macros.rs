@@ -0,0 +1,125 @@
+//! Text macros: strings of the keymap typed through the host's keyboard layout.
+//!
+//! The strings are encoded as Vial macros, so rmk plays them on `Action::TriggerMacro` and Vial's
+//! macro editor shows them. Characters the layout has no key for are entered by their code point,
+//! as `UNICODE_INPUT` says.
+
+use defmt::warn;
+use heapless::Vec;
+use rmk::action::{Action, KeyAction};
+use rmk::config::KeyboardMacrosConfig;
+use rmk::keycode::{KeyCode, ModifierCombination};
+
+use crate::keymap::{TEXT_MACROS, UNICODE_INPUT, UnicodeInput, host_layout};
+
+/// Vial's macro encoding: the prefix starts a key operation, a zero ends the macro
+const SS_QMK_PREFIX: u8 = 1;
+const SS_TAP_CODE: u8 = 1;
+const SS_DOWN_CODE: u8 = 2;
+const SS_UP_CODE: u8 = 3;
+
+const CTRL_SHIFT: ModifierCombination =
+    ModifierCombination::new_from(false, false, false, true, true);
+
+/// Operations of a character, enough for a code point entered with its modifiers
+type Ops = Vec<u8, 64>;
+
+/// rmk's macros, `TEXT_MACROS` one after the other
+pub(crate) fn get_keyboard_macros_config() -> KeyboardMacrosConfig {
+    KeyboardMacrosConfig {
+        macro_sequences: encode_macros(&TEXT_MACROS),
+    }
+}
+
+/// The macros ended by a zero each. A macro is cut at the first character that doesn't fit.
+fn encode_macros<const N: usize>(texts: &[&str]) -> [u8; N] {
+    let mut buf = [0; N];
+    let mut len = 0;
+    for (index, text) in texts.iter().enumerate() {
+        for c in text.chars() {
+            let ops = char_ops(c);
+            // Keep room for the zero
+            if len + ops.len() >= N {
+                warn!("Text macro {} doesn't fit", index);
+                break;
+            }
+            buf[len..len + ops.len()].copy_from_slice(&ops);
+            len += ops.len();
+        }
+        // The zero is already there
+        len = (len + 1).min(N);
+    }
+    buf
+}
+
+fn char_ops(c: char) -> Ops {
+    let mut ops = Ops::new();
+    let Some(action) = host_layout::char_action(c) else {
+        unicode_ops(&mut ops, c);
+        return ops;
+    };
+    tap(&mut ops, action);
+    if host_layout::DEAD_KEYS.contains(&c) {
+        tap(&mut ops, key(KeyCode::Space));
+    }
+    ops
+}
+
+fn unicode_ops(ops: &mut Ops, c: char) {
+    match UNICODE_INPUT {
+        UnicodeInput::Linux => tap(
+            ops,
+            KeyAction::Single(Action::KeyWithModifier(KeyCode::U, CTRL_SHIFT)),
+        ),
+        UnicodeInput::WinCompose => {
+            tap(ops, key(KeyCode::RAlt));
+            tap(ops, key(KeyCode::U));
+        }
+    }
+    let code = c as u32;
+    let digits = (u32::BITS - code.leading_zeros()).div_ceil(4).max(1);
+    for digit in (0..digits).rev() {
+        let digit = char::from_digit((code >> (digit * 4)) & 0xF, 16).unwrap();
+        if let Some(action) = host_layout::char_action(digit) {
+            tap(ops, action);
+        }
+    }
+    match UNICODE_INPUT {
+        UnicodeInput::Linux => tap(ops, key(KeyCode::Space)),
+        UnicodeInput::WinCompose => tap(ops, key(KeyCode::Enter)),
+    }
+}
+
+fn key(key: KeyCode) -> KeyAction {
+    KeyAction::Single(Action::Key(key))
+}
+
+/// Tap the key of `action` with its modifiers held
+fn tap(ops: &mut Ops, action: KeyAction) {
+    let (key, modifiers) = match action {
+        KeyAction::Single(Action::Key(key)) => (key, 0),
+        KeyAction::Single(Action::KeyWithModifier(key, modifiers)) => {
+            (key, modifiers.to_hid_modifiers().into_bits())
+        }
+        _ => return,
+    };
+    // Firmware keys have no usage on the HID keyboard page
+    let Ok(key) = u8::try_from(key as u16) else {
+        return;
+    };
+    // The modifier keys are 0xE0 to 0xE7, in the order of the report's modifier bits
+    let modifier_keys = (0..8u8)
+        .filter(|bit| modifiers & (1 << bit) != 0)
+        .map(|bit| 0xE0 + bit);
+    let mut op = |code: u8, key: u8| {
+        // Sized for the longest character
+        let _ = ops.extend_from_slice(&[SS_QMK_PREFIX, code, key]);
+    };
+    for modifier in modifier_keys.clone() {
+        op(SS_DOWN_CODE, modifier);
+    }
+    op(SS_TAP_CODE, key);
+    for modifier in modifier_keys {
+        op(SS_UP_CODE, modifier);
+    }
+}
@@ -0,0 +1,130 @@
+//! Firmware updates of the peripheral, streamed by the central over the split link.
+//!
+//! The image is written to the DFU partition of the internal flash and verified. The loader
+//! installs it on the next boot. Erasing the partition blocks for a second or two, keys aren't
+//! scanned in the meantime.
+
+use defmt::{Format, info, warn};
+use embassy_stm32::flash::{self, Blocking, Flash};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Timer;
+
+use crate::boot_state::{self, BootState};
+use crate::partitions::{PERIPHERAL_ACTIVE_SIZE, PERIPHERAL_DFU_OFFSET, PERIPHERAL_DFU_SIZE};
+use crate::split_link::{LINK_OUTBOX, LinkMessage, UPDATE_CHUNK_SIZE};
+
+/// Update messages of the central
+pub(crate) static UPDATE_MESSAGES: Channel<CriticalSectionRawMutex, LinkMessage, 2> =
+    Channel::new();
+
+#[derive(Format)]
+enum UpdateError {
+    NotStarted,
+    TooLarge,
+    Flash(flash::Error),
+    CrcMismatch,
+}
+
+impl From<flash::Error> for UpdateError {
+    fn from(e: flash::Error) -> Self {
+        Self::Flash(e)
+    }
+}
+
+struct Image {
+    size: u32,
+    crc: u32,
+    received: u32,
+}
+
+pub(crate) struct UpdateReceiver<'d> {
+    flash: Flash<'d, Blocking>,
+    image: Option<Image>,
+}
+
+impl<'d> UpdateReceiver<'d> {
+    pub(crate) fn new(flash: Flash<'d, Blocking>) -> Self {
+        Self { flash, image: None }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        loop {
+            let reply = match UPDATE_MESSAGES.receive().await {
+                LinkMessage::UpdateBegin { size, crc } => self.begin(size, crc),
+                LinkMessage::UpdateChunk { offset, data } => self.write(offset, &data),
+                LinkMessage::UpdateFinish => self.finish(),
+                message => {
+                    warn!("Unexpected link message {}", message);
+                    continue;
+                }
+            };
+            match reply {
+                Ok(LinkMessage::UpdateDone) => {
+                    LINK_OUTBOX.send(LinkMessage::UpdateDone).await;
+                    info!("Rebooting to install the update");
+                    // Give the answer time to go out
+                    Timer::after_millis(100).await;
+                    cortex_m::peripheral::SCB::sys_reset()
+                }
+                Ok(reply) => LINK_OUTBOX.send(reply).await,
+                Err(e) => {
+                    warn!("Update failed: {}", e);
+                    self.image = None;
+                    LINK_OUTBOX.send(LinkMessage::UpdateFailed).await
+                }
+            }
+        }
+    }
+
+    fn begin(&mut self, size: u32, crc: u32) -> Result<LinkMessage, UpdateError> {
+        info!("Receiving update of {} bytes", size);
+        // The loader only installs images that fit the active firmware
+        if size > PERIPHERAL_ACTIVE_SIZE {
+            return Err(UpdateError::TooLarge);
+        }
+        self.flash.blocking_erase(
+            PERIPHERAL_DFU_OFFSET,
+            PERIPHERAL_DFU_OFFSET + PERIPHERAL_DFU_SIZE,
+        )?;
+        self.image = Some(Image {
+            size,
+            crc,
+            received: 0,
+        });
+        Ok(LinkMessage::UpdateAck { offset: 0 })
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<LinkMessage, UpdateError> {
+        let image = self.image.as_mut().ok_or(UpdateError::NotStarted)?;
+        // Repeated or skipped chunks are answered with what we expect instead
+        if offset == image.received && offset + data.len() as u32 <= image.size {
+            // Pad with erased bytes, the flash is written in whole words
+            let mut buf = [0xFF; UPDATE_CHUNK_SIZE];
+            buf[..data.len()].copy_from_slice(data);
+            let len = data.len().next_multiple_of(4);
+            self.flash
+                .blocking_write(PERIPHERAL_DFU_OFFSET + offset, &buf[..len])?;
+            image.received += data.len() as u32;
+        }
+        Ok(LinkMessage::UpdateAck {
+            offset: image.received,
+        })
+    }
+
+    fn finish(&mut self) -> Result<LinkMessage, UpdateError> {
+        let image = self.image.take().ok_or(UpdateError::NotStarted)?;
+        if image.received != image.size
+            || boot_state::flash_crc(&mut self.flash, PERIPHERAL_DFU_OFFSET, image.size)?
+                != image.crc
+        {
+            return Err(UpdateError::CrcMismatch);
+        }
+        BootState::InstallInternal {
+            size: image.size,
+            crc: image.crc,
+        }
+        .write(&mut self.flash)?;
+        Ok(LinkMessage::UpdateDone)
+    }
+}
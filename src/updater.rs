@@ -0,0 +1,384 @@
+//! Firmware updates of both halves through the central.
+//!
+//! The host sends the image over raw HID. It is staged in the external flash and checked against
+//! its CRC. The updater works on the requests in its own task, the host polls the status for the
+//! outcome. The central's own image is installed by the loader on the next boot, the peripheral's
+//! is streamed over the split link and installed by the peripheral's loader.
+//!
+//! An updated central runs under the watchdog until it confirms it's healthy, once USB is
+//! configured or the peripheral answered over the split link. Otherwise the loader rolls back to
+//! the previous firmware.
+
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use defmt::{Format, info, warn};
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_stm32::peripherals::IWDG;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+use crate::boot_state::{self, BootState};
+use crate::crc::Crc32;
+use crate::link_messages::UPDATE_REPLIES;
+use crate::partitions::{
+    CENTRAL_ACTIVE_SIZE, CENTRAL_STAGING_SIZE, PERIPHERAL_ACTIVE_SIZE, PERIPHERAL_STAGING_SIZE,
+};
+use crate::split_link::{LINK_OUTBOX, LinkMessage, UPDATE_CHUNK_SIZE};
+
+/// Image bytes in one raw HID report
+pub(crate) const MAX_DATA_LEN: usize = 25;
+
+/// Erasing its DFU partition takes the peripheral a while
+const BEGIN_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRIES: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum UpdateTarget {
+    Central,
+    Peripheral,
+}
+
+pub(crate) enum UpdateRequest {
+    /// Start staging an image
+    Begin {
+        target: UpdateTarget,
+        size: u32,
+        crc: u32,
+    },
+    /// Part of the image, in order
+    Data {
+        offset: u32,
+        data: Vec<u8, MAX_DATA_LEN>,
+    },
+    /// Verify the image and install it
+    Finish,
+}
+
+/// Reasons an update failed, sent to the host as the status of its command.
+#[derive(Clone, Copy, Format)]
+#[repr(u8)]
+pub(crate) enum UpdateError {
+    /// The image is being sent to the peripheral, or the previous request is still being worked on
+    Busy = 1,
+    BadTarget,
+    BadSize,
+    NotStarted,
+    BadOffset,
+    Flash,
+    CrcMismatch,
+    /// The peripheral didn't answer
+    LinkTimeout,
+    /// The peripheral gave up on the update
+    Rejected,
+    /// Writing the boot state for the loader failed
+    BootState,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub(crate) enum UpdateState {
+    Idle,
+    Receiving,
+    Sending,
+    Done,
+    Failed,
+}
+
+static STATE: AtomicU8 = AtomicU8::new(UpdateState::Idle as u8);
+/// Bytes received from the host, or acknowledged by the peripheral while sending
+static PROGRESS: AtomicU32 = AtomicU32::new(0);
+
+/// The last error, 0 while there is none
+static ERROR: AtomicU8 = AtomicU8::new(0);
+
+static REQUESTS: Channel<CriticalSectionRawMutex, UpdateRequest, 1> = Channel::new();
+/// The firmware is confirmed healthy and has to feed the watchdog
+static CONFIRMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// USB is configured or the peripheral answered, the firmware works
+static HEALTHY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The firmware got through to the host or the peripheral, an update of it is kept.
+pub(crate) fn healthy() {
+    HEALTHY.signal(());
+}
+
+/// Hand a request of the host to the updater, without waiting for the flash. `Busy` until the
+/// updater took the previous one.
+pub(crate) fn request(request: UpdateRequest) -> Result<(), UpdateError> {
+    if STATE.load(Ordering::Relaxed) == UpdateState::Sending as u8 {
+        return Err(UpdateError::Busy);
+    }
+    REQUESTS.try_send(request).map_err(|_| UpdateError::Busy)
+}
+
+/// State of the update, its progress in bytes and the `UpdateError` it failed with, if it did
+pub(crate) fn status() -> (u8, u32, u8) {
+    (
+        STATE.load(Ordering::Relaxed),
+        PROGRESS.load(Ordering::Relaxed),
+        ERROR.load(Ordering::Relaxed),
+    )
+}
+
+fn set_state(state: UpdateState) {
+    STATE.store(state as u8, Ordering::Relaxed);
+}
+
+fn fail(error: UpdateError) {
+    set_state(UpdateState::Failed);
+    ERROR.store(error as u8, Ordering::Relaxed);
+}
+
+struct Image {
+    target: UpdateTarget,
+    size: u32,
+    crc: u32,
+    received: u32,
+}
+
+pub(crate) struct Updater<'d, F> {
+    /// Partitions of the external flash for the images of the halves
+    central_staging: F,
+    peripheral_staging: F,
+    /// Internal flash, for the boot state
+    flash: Flash<'d, Blocking>,
+    image: Option<Image>,
+}
+
+impl<'d, F: NorFlash> Updater<'d, F> {
+    pub(crate) fn new(
+        central_staging: F,
+        peripheral_staging: F,
+        flash: Flash<'d, Blocking>,
+    ) -> Self {
+        Self {
+            central_staging,
+            peripheral_staging,
+            flash,
+            image: None,
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        self.confirm_boot().await;
+        loop {
+            let result = match REQUESTS.receive().await {
+                UpdateRequest::Begin { target, size, crc } => self.begin(target, size, crc),
+                UpdateRequest::Data { offset, data } => self.write(offset, &data).await,
+                UpdateRequest::Finish => self.finish().await,
+            };
+            let image = match result {
+                Ok(Some(image)) => image,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Update failed: {}", e);
+                    fail(e);
+                    self.image = None;
+                    continue;
+                }
+            };
+
+            match image.target {
+                UpdateTarget::Central => self.install(&image).await,
+                UpdateTarget::Peripheral => self.send_to_peripheral(&image).await,
+            }
+        }
+    }
+
+    /// Confirm an update of the central, so the loader keeps it.
+    async fn confirm_boot(&mut self) {
+        if BootState::read(&mut self.flash) == Some(BootState::Testing) {
+            // Not feeding the watchdog until here, if the firmware hangs or gets through to
+            // neither the host nor the peripheral it resets
+            HEALTHY.wait().await;
+            match boot_state::clear(&mut self.flash) {
+                Ok(()) => info!("Update confirmed"),
+                Err(e) => warn!("Failed to confirm update: {}", e),
+            }
+        }
+        CONFIRMED.signal(());
+    }
+
+    /// Leave the central's image to the loader and reboot.
+    async fn install(&mut self, image: &Image) {
+        let state = BootState::InstallExternal {
+            size: image.size,
+            crc: image.crc,
+        };
+        if let Err(e) = state.write(&mut self.flash) {
+            warn!("Failed to write boot state: {}", e);
+            fail(UpdateError::BootState);
+            return;
+        }
+        set_state(UpdateState::Done);
+        info!("Rebooting to install the update");
+        // Give the host time to poll the status
+        Timer::after_millis(100).await;
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    fn staging(&mut self, target: UpdateTarget) -> &mut F {
+        match target {
+            UpdateTarget::Central => &mut self.central_staging,
+            UpdateTarget::Peripheral => &mut self.peripheral_staging,
+        }
+    }
+
+    /// The `Ok` of requests is the image to install once it is complete
+    fn begin(
+        &mut self,
+        target: UpdateTarget,
+        size: u32,
+        crc: u32,
+    ) -> Result<Option<Image>, UpdateError> {
+        // The loaders refuse images larger than the active firmware
+        let max_size = match target {
+            UpdateTarget::Central => CENTRAL_ACTIVE_SIZE.min(CENTRAL_STAGING_SIZE),
+            UpdateTarget::Peripheral => PERIPHERAL_ACTIVE_SIZE.min(PERIPHERAL_STAGING_SIZE),
+        };
+        if size == 0 || size > max_size {
+            return Err(UpdateError::BadSize);
+        }
+        info!("Staging update of {} bytes for the {}", size, target);
+        self.image = Some(Image {
+            target,
+            size,
+            crc,
+            received: 0,
+        });
+        set_state(UpdateState::Receiving);
+        ERROR.store(0, Ordering::Relaxed);
+        PROGRESS.store(0, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<Option<Image>, UpdateError> {
+        let image = self.image.as_ref().ok_or(UpdateError::NotStarted)?;
+        if offset != image.received {
+            return Err(UpdateError::BadOffset);
+        }
+        let end = offset + data.len() as u32;
+        if end > image.size {
+            return Err(UpdateError::BadSize);
+        }
+        let staging = self.staging(image.target);
+        // Erase sectors as the image reaches them, erasing everything up front would stall the
+        // host's first write
+        let erased = offset.next_multiple_of(F::ERASE_SIZE as u32);
+        if end > erased {
+            staging
+                .erase(erased, erased + F::ERASE_SIZE as u32)
+                .await
+                .map_err(|_| UpdateError::Flash)?;
+        }
+        staging
+            .write(offset, data)
+            .await
+            .map_err(|_| UpdateError::Flash)?;
+        if let Some(image) = self.image.as_mut() {
+            image.received = end;
+        }
+        PROGRESS.store(end, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    async fn finish(&mut self) -> Result<Option<Image>, UpdateError> {
+        let image = self.image.take().ok_or(UpdateError::NotStarted)?;
+        if image.received != image.size {
+            return Err(UpdateError::BadSize);
+        }
+        let staging = self.staging(image.target);
+        let mut crc = Crc32::new();
+        let mut buf = [0; 256];
+        for offset in (0..image.size).step_by(buf.len()) {
+            let chunk = &mut buf[..(image.size - offset).min(256) as usize];
+            staging
+                .read(offset, chunk)
+                .await
+                .map_err(|_| UpdateError::Flash)?;
+            crc.update(chunk);
+        }
+        if crc.finish() != image.crc {
+            return Err(UpdateError::CrcMismatch);
+        }
+        Ok(Some(image))
+    }
+
+    /// Stream the peripheral's image over the link.
+    async fn send_to_peripheral(&mut self, image: &Image) {
+        set_state(UpdateState::Sending);
+        PROGRESS.store(0, Ordering::Relaxed);
+        match self.stream(image).await {
+            Ok(()) => {
+                info!("Peripheral installs the update");
+                set_state(UpdateState::Done);
+            }
+            Err(e) => {
+                warn!("Failed to send the update to the peripheral: {}", e);
+                fail(e);
+            }
+        }
+    }
+
+    async fn stream(&mut self, image: &Image) -> Result<(), UpdateError> {
+        let begin = LinkMessage::UpdateBegin {
+            size: image.size,
+            crc: image.crc,
+        };
+        let mut offset = expect_ack(exchange(begin, BEGIN_TIMEOUT).await?)?;
+        while offset < image.size {
+            let mut data = Vec::new();
+            let len = (image.size - offset).min(UPDATE_CHUNK_SIZE as u32) as usize;
+            data.resize(len, 0).unwrap();
+            self.peripheral_staging
+                .read(offset, &mut data)
+                .await
+                .map_err(|_| UpdateError::Flash)?;
+            let chunk = LinkMessage::UpdateChunk { offset, data };
+            offset = expect_ack(exchange(chunk, REPLY_TIMEOUT).await?)?;
+            PROGRESS.store(offset, Ordering::Relaxed);
+        }
+        match exchange(LinkMessage::UpdateFinish, BEGIN_TIMEOUT).await? {
+            LinkMessage::UpdateDone => Ok(()),
+            _ => Err(UpdateError::Rejected),
+        }
+    }
+}
+
+/// Feed the watchdog the loader starts for an updated central, once the update is confirmed.
+///
+/// The watchdog keeps running until the next power cycle, so it is fed in every boot after an
+/// update. Feeding it when it isn't running does nothing.
+pub(crate) async fn run_watchdog(mut watchdog: IndependentWatchdog<'_, IWDG>) {
+    CONFIRMED.wait().await;
+    loop {
+        watchdog.pet();
+        Timer::after_secs(1).await;
+    }
+}
+
+/// Send `message` to the peripheral and wait for its answer, retrying when it doesn't come.
+async fn exchange(message: LinkMessage, timeout: Duration) -> Result<LinkMessage, UpdateError> {
+    for _ in 0..RETRIES {
+        LINK_OUTBOX.send(message.clone()).await;
+        match with_timeout(timeout, UPDATE_REPLIES.receive()).await {
+            Ok(LinkMessage::UpdateFailed) => return Err(UpdateError::Rejected),
+            Ok(reply) => return Ok(reply),
+            Err(_) => warn!("No answer of the peripheral to {}", message),
+        }
+    }
+    Err(UpdateError::LinkTimeout)
+}
+
+/// The offset the peripheral expects next
+fn expect_ack(reply: LinkMessage) -> Result<u32, UpdateError> {
+    match reply {
+        LinkMessage::UpdateAck { offset } => Ok(offset),
+        _ => Err(UpdateError::Rejected),
+    }
+}
@@ -0,0 +1,22 @@
+//! VBUS handling for self-powered board revisions, enabled with the `vbus-detection` feature.
+
+use defmt::info;
+use embassy_stm32::exti::ExtiInput;
+
+use crate::key_filter;
+
+/// Follow cable unplug/replug events on the VBUS sense pin.
+///
+/// The USB driver re-enumerates by itself once VBUS comes back. Keys held while the cable was
+/// gone would stay pressed in rmk, so `key_filter` hands rmk their releases and ends Caps Word and
+/// a leader sequence, and the keyboard starts over like the host. A pending one-shot key times out
+/// on its own.
+pub(crate) async fn run_vbus_monitor(mut vbus: ExtiInput<'_>) {
+    loop {
+        vbus.wait_for_low().await;
+        info!("USB cable unplugged");
+        vbus.wait_for_high().await;
+        info!("USB cable plugged in, releasing all keys");
+        key_filter::RELEASE_ALL.signal(());
+    }
+}
@@ -0,0 +1,60 @@
+//! Keys handled by the firmware instead of rmk.
+//!
+//! rmk doesn't act on its user keycodes on wired keyboards, they're picked up from the controller
+//! events here.
+
+use defmt::warn;
+use rmk::action::{Action, KeyAction};
+use rmk::event::ControllerEvent;
+
+use crate::controller::ControllerEvents;
+use crate::dfu::DFU_REQUEST;
+use crate::keymap::{DFU, RESET_STORAGE, TAPPING_TERM_DOWN, TAPPING_TERM_STEP, TAPPING_TERM_UP};
+use crate::settings;
+
+pub(crate) static EVENTS: ControllerEvents = ControllerEvents::new();
+
+pub(crate) async fn run_user_keys() {
+    loop {
+        let ControllerEvent::Key(event, KeyAction::Single(Action::Key(key))) =
+            EVENTS.receive().await
+        else {
+            continue;
+        };
+        // Act on release, so the host has seen the key go up
+        if event.pressed {
+            continue;
+        }
+        match key {
+            DFU => DFU_REQUEST.signal(()),
+            RESET_STORAGE => {
+                if !settings::reset().await {
+                    warn!("Failed to reset the storage");
+                }
+            }
+            TAPPING_TERM_UP | TAPPING_TERM_DOWN => {
+                change_tapping_term(key == TAPPING_TERM_UP).await
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Save the tapping term one step longer or shorter, the settings reboot to apply it.
+async fn change_tapping_term(longer: bool) {
+    let Some(mut tap_hold) = settings::tap_hold() else {
+        return;
+    };
+    tap_hold.hold_timeout_ms = if longer {
+        tap_hold.hold_timeout_ms.saturating_add(TAPPING_TERM_STEP)
+    } else {
+        // A tapping term of zero would make every tap a hold
+        tap_hold
+            .hold_timeout_ms
+            .saturating_sub(TAPPING_TERM_STEP)
+            .max(TAPPING_TERM_STEP)
+    };
+    if !settings::save_tap_hold(tap_hold).await {
+        warn!("Failed to save the tapping term");
+    }
+}
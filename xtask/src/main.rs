@@ -0,0 +1,309 @@
+//! Host tooling for the firmware.
+//!
+//! `package` turns a firmware ELF into the files used for flashing without a probe:
+//! a raw `.bin`, an Intel `.hex` and a `.uf2` for UF2 bootloaders.
+//!
+//! ```shell
+//! cargo run --manifest-path xtask/Cargo.toml -- package <elf> <output prefix> [--family stm32f4|rp2040] [--offset <bytes>]
+//! ```
+//!
+//! The tests run on the host with `cargo make test-xtask`, along with those of the build script's
+//! modules.
+
+// Modules of the firmware's build script, tested on the host with the xtask
+#[cfg(test)]
+#[path = "../../build/keyboard_uid.rs"]
+mod keyboard_uid;
+#[cfg(test)]
+#[path = "../../build/kle.rs"]
+mod kle;
+
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, ProgramHeader};
+use std::path::Path;
+use std::process::ExitCode;
+use std::{env, fs};
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+#[derive(Clone, Copy)]
+enum Family {
+    Stm32f4,
+    Rp2040,
+}
+
+impl Family {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "stm32f4" => Ok(Family::Stm32f4),
+            "rp2040" => Ok(Family::Rp2040),
+            _ => Err(format!("Unknown family {name}, expected stm32f4 or rp2040")),
+        }
+    }
+
+    /// UF2 family id, see the list in `scripts/uf2conv.py`
+    fn id(self) -> u32 {
+        match self {
+            Family::Stm32f4 => 0x5775_5A57,
+            Family::Rp2040 => 0xE48B_FF56,
+        }
+    }
+
+    /// Start of the internal flash
+    fn flash_base(self) -> u32 {
+        match self {
+            Family::Stm32f4 => 0x0800_0000,
+            Family::Rp2040 => 0x1000_0000,
+        }
+    }
+}
+
+/// Contiguous flash contents of a firmware, gaps filled with the erased value.
+struct Image {
+    start: u32,
+    data: Vec<u8>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("package") => package(&args[1..]),
+        _ => Err("Usage: xtask package <elf> <output prefix> [--family stm32f4|rp2040] [--offset <bytes>]".to_owned()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn package(args: &[String]) -> Result<(), String> {
+    let mut positional = Vec::new();
+    let mut family = Family::Stm32f4;
+    let mut offset = 0;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--family" => family = Family::parse(args.next().ok_or("--family needs a value")?)?,
+            "--offset" => offset = parse_number(args.next().ok_or("--offset needs a value")?)?,
+            _ => positional.push(arg),
+        }
+    }
+    let [elf, prefix] = positional[..] else {
+        return Err("package needs an ELF file and an output prefix".to_owned());
+    };
+
+    let image = load_image(Path::new(elf))?;
+    // The firmware has to be linked for the slot behind the bootloader, otherwise it won't boot
+    let expected_start = family.flash_base() + offset;
+    if image.start != expected_start {
+        return Err(format!(
+            "{elf} starts at {:#010x}, expected {expected_start:#010x} (flash base + offset {offset:#x})",
+            image.start
+        ));
+    }
+
+    write(&format!("{prefix}.bin"), &image.data)?;
+    write(&format!("{prefix}.hex"), to_ihex(&image).as_bytes())?;
+    write(&format!("{prefix}.uf2"), &to_uf2(&image, family))?;
+    println!(
+        "Packaged {elf}: {} bytes at {:#010x}",
+        image.data.len(),
+        image.start
+    );
+    Ok(())
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|e| format!("Invalid number {s}: {e}"))
+}
+
+fn write(path: &str, contents: &[u8]) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| format!("Cannot write {path}: {e}"))
+}
+
+/// Collect the loadable segments at their load (flash) addresses
+fn load_image(path: &Path) -> Result<Image, String> {
+    let data = fs::read(path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+    let elf = ElfFile32::<object::Endianness>::parse(&*data)
+        .map_err(|e| format!("Cannot parse {}: {e}", path.display()))?;
+    let endian = elf.endian();
+
+    let mut segments = Vec::new();
+    for header in elf.elf_program_headers() {
+        if header.p_type(endian) != PT_LOAD || header.p_filesz(endian) == 0 {
+            continue;
+        }
+        let contents = header
+            .data(endian, &*data)
+            .map_err(|_| "Segment data out of bounds".to_owned())?;
+        segments.push((header.p_paddr(endian), contents));
+    }
+    let start = segments
+        .iter()
+        .map(|(addr, _)| *addr)
+        .min()
+        .ok_or("No loadable segments")?;
+    let end = segments
+        .iter()
+        .map(|(addr, contents)| addr + contents.len() as u32)
+        .max()
+        .unwrap();
+
+    let mut image = vec![0xFF; (end - start) as usize];
+    for (addr, contents) in segments {
+        let at = (addr - start) as usize;
+        image[at..at + contents.len()].copy_from_slice(contents);
+    }
+    Ok(Image { start, data: image })
+}
+
+fn to_uf2(image: &Image, family: Family) -> Vec<u8> {
+    let chunks = image.data.chunks(UF2_PAYLOAD_SIZE);
+    let num_blocks = chunks.len() as u32;
+    let mut out = Vec::with_capacity(num_blocks as usize * UF2_BLOCK_SIZE);
+    for (block_no, chunk) in chunks.enumerate() {
+        let target_addr = image.start + (block_no * UF2_PAYLOAD_SIZE) as u32;
+        for word in [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            UF2_FLAG_FAMILY_ID_PRESENT,
+            target_addr,
+            UF2_PAYLOAD_SIZE as u32,
+            block_no as u32,
+            num_blocks,
+            family.id(),
+        ] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        let mut payload = [0u8; 476];
+        payload[..chunk.len()].copy_from_slice(chunk);
+        // Pad the last block with the erased value
+        payload[chunk.len()..UF2_PAYLOAD_SIZE].fill(0xFF);
+        out.extend_from_slice(&payload);
+        out.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+    }
+    out
+}
+
+fn to_ihex(image: &Image) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    for (i, chunk) in image.data.chunks(16).enumerate() {
+        let addr = image.start + (i * 16) as u32;
+        if upper != Some(addr >> 16) {
+            upper = Some(addr >> 16);
+            out += &ihex_record(0, 0x04, &((addr >> 16) as u16).to_be_bytes());
+        }
+        out += &ihex_record(addr as u16, 0x00, chunk);
+    }
+    out += &ihex_record(0, 0x01, &[]);
+    out
+}
+
+fn ihex_record(addr: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+    bytes.push(checksum);
+    let hex: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    format!(":{hex}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(block: &[u8], index: usize) -> u32 {
+        u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn uf2_blocks_cover_the_image() {
+        let image = Image {
+            start: 0x0800_8000,
+            data: (0..300).map(|i| i as u8).collect(),
+        };
+        let uf2 = to_uf2(&image, Family::Stm32f4);
+        assert_eq!(uf2.len(), 2 * UF2_BLOCK_SIZE);
+
+        for (block_no, block) in uf2.chunks(UF2_BLOCK_SIZE).enumerate() {
+            assert_eq!(word(block, 0), UF2_MAGIC_START0);
+            assert_eq!(word(block, 1), UF2_MAGIC_START1);
+            assert_eq!(word(block, 2), UF2_FLAG_FAMILY_ID_PRESENT);
+            assert_eq!(
+                word(block, 3),
+                0x0800_8000 + (block_no * UF2_PAYLOAD_SIZE) as u32
+            );
+            assert_eq!(word(block, 4), UF2_PAYLOAD_SIZE as u32);
+            assert_eq!(word(block, 5), block_no as u32);
+            assert_eq!(word(block, 6), 2);
+            assert_eq!(word(block, 7), 0x5775_5A57);
+            assert_eq!(word(block, 127), UF2_MAGIC_END);
+        }
+        let second = &uf2[UF2_BLOCK_SIZE + 32..];
+        assert_eq!(&second[..44], &image.data[256..]);
+        // The last block is padded with the erased value, the rest of the data area with zeros
+        assert!(second[44..UF2_PAYLOAD_SIZE].iter().all(|&b| b == 0xFF));
+        assert!(second[UF2_PAYLOAD_SIZE..476].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn uf2_family_ids() {
+        let image = Image {
+            start: 0x1000_0000,
+            data: vec![0; 4],
+        };
+        assert_eq!(word(&to_uf2(&image, Family::Rp2040), 7), 0xE48B_FF56);
+        assert!(Family::parse("stm32f1").is_err());
+    }
+
+    #[test]
+    fn ihex_records() {
+        assert_eq!(ihex_record(0, 0x01, &[]), ":00000001FF\n");
+        assert_eq!(ihex_record(0, 0x04, &[0x08, 0x00]), ":020000040800F2\n");
+        assert_eq!(
+            ihex_record(0x0010, 0x00, &[0xDE, 0xAD, 0xBE, 0xEF]),
+            ":04001000DEADBEEFB4\n"
+        );
+    }
+
+    #[test]
+    fn ihex_sets_the_upper_address_when_crossing_64k() {
+        let image = Image {
+            start: 0x0800_FFF0,
+            data: vec![0xAA; 32],
+        };
+        let hex = to_ihex(&image);
+        let records: Vec<_> = hex.lines().collect();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0], ":020000040800F2");
+        assert!(records[1].starts_with(":10FFF000"));
+        assert_eq!(records[2], ":020000040801F1");
+        assert!(records[3].starts_with(":10000000"));
+        assert_eq!(records[4], ":00000001FF");
+    }
+
+    #[test]
+    fn numbers_in_decimal_and_hex() {
+        assert_eq!(parse_number("32768"), Ok(0x8000));
+        assert_eq!(parse_number("0x8000"), Ok(0x8000));
+        assert!(parse_number("8k").is_err());
+    }
+}